@@ -0,0 +1,80 @@
+use crate::config::PersonaConfig;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fmt::Write, path::PathBuf};
+
+/// Newest catalog in this build. Configs that do not set `catalog:` get
+/// version 1, so upgrading never changes what an existing config does.
+pub const LATEST: u32 = 1;
+
+/// Every catalog shipped so far, by version. Released versions are frozen.
+const CATALOGS: &[(u32, &str)] = &[(1, include_str!("../catalog/v1.yml"))];
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub description: String,
+    pub persona: PersonaConfig,
+}
+
+/// The builtin personas of catalog `version`, by name.
+pub fn catalog(version: u32) -> Result<BTreeMap<String, Entry>> {
+    let Some((_, raw)) = CATALOGS.iter().find(|(v, _)| *v == version) else {
+        bail!("no builtin catalog v{} (this build has v1 to v{})", version, LATEST);
+    };
+    serde_yaml::from_str(raw).with_context(|| format!("builtin catalog v{}", version))
+}
+
+/// Resolve a `use:` reference, `builtin/<name>` or `builtin/<name>@<version>`,
+/// taking `default_version` when it is not pinned. Returns the pinned name
+/// and the persona.
+pub fn lookup(reference: &str, default_version: u32) -> Result<(String, PersonaConfig)> {
+    let Some(rest) = reference.strip_prefix("builtin/") else { bail!("`use` takes builtin/<name>, got `{}`", reference) };
+    let (name, version) = match rest.split_once('@') {
+        Some((name, v)) => (name, v.parse().with_context(|| format!("bad catalog version in `{}`", reference))?),
+        None => (rest, default_version),
+    };
+    let entry = catalog(version)?.remove(name).with_context(|| format!("no `{}` in builtin catalog v{} (see `sage-valve personas catalog`)", name, version))?;
+    let mut persona = entry.persona;
+    persona.origin.file = PathBuf::from(format!("builtin catalog v{}", version));
+    Ok((format!("builtin/{}@{}", name, version), persona))
+}
+
+/// `personas catalog`: the builtins of `version` (default latest) with
+/// what each watches.
+pub fn describe(version: Option<u32>) -> Result<String> {
+    let version = version.unwrap_or(LATEST);
+    let mut out = String::new();
+    let _ = writeln!(out, "builtin catalog v{} (pin with `catalog: {}` or `use: builtin/<name>@{}`)\n", version, version, version);
+    for (name, entry) in catalog(version)? {
+        let p = &entry.persona;
+        let _ = writeln!(out, "{:<20}{}", name, entry.description);
+        let _ = writeln!(out, "{:<20}severity {}, filters {}", "", p.severity.unwrap_or_default(), serde_json::json!(p.filters.clone().unwrap_or_default()));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ValveConfig;
+
+    #[test]
+    fn test_every_catalog_compiles() {
+        for (version, _) in CATALOGS {
+            let personas = catalog(*version).expect("catalog parses").into_iter().map(|(name, e)| (name, e.persona)).collect();
+            crate::config::compile(&ValveConfig { personas, include: vec![], catalog: None }).expect("catalog compiles");
+        }
+        assert_eq!(CATALOGS.last().map(|(v, _)| *v), Some(LATEST));
+    }
+
+    #[test]
+    fn test_lookup_pins_versions() {
+        let (pinned, guardian) = lookup("builtin/guardian", 1).expect("guardian");
+        assert_eq!(pinned, "builtin/guardian@1");
+        assert!(guardian.filters.unwrap().contains(&"**/.env*".to_string()));
+        assert!(lookup("builtin/guardian@99", 1).is_err());
+        assert!(lookup("builtin/nobody", 1).is_err());
+        assert!(lookup("guardian", 1).is_err());
+    }
+}
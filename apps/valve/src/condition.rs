@@ -0,0 +1,281 @@
+//! The `conditions:` predicate language. Each condition is an expression
+//! over facts about a change; a persona fires only if all of them hold.
+//!
+//! ```text
+//! expr  := and ("||" and)*          (also `or`)
+//! and   := unary ("&&" unary)*      (also `and`)
+//! unary := "!" unary | "(" expr ")" | fact [op value]
+//! op    := == != < <= > >= matches
+//! ```
+//!
+//! Facts: `kind` (create, modify, delete, rename), `size` (bytes; `10k`, `2mb`),
+//! `lines`, `branch`, `hour` (0-23, local), `weekday` (mon..sun), `new`
+//! and `tracked` (by git). `matches` takes a glob: `branch matches "release/*"`.
+
+use crate::persona::Facts;
+use globset::{Glob, GlobMatcher};
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    /// A boolean fact on its own
+    Flag(Fact),
+    Compare(Fact, Op, Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fact { Kind, Size, Lines, Branch, Hour, Weekday, New, Tracked }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op { Eq, Ne, Lt, Le, Gt, Ge, Matches }
+
+#[derive(Debug, Clone)]
+pub enum Value { Number(u64), Text(String), Glob(GlobMatcher) }
+
+enum Type { Number, Text, Bool }
+
+impl Fact {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "kind" => Fact::Kind, "size" => Fact::Size, "lines" => Fact::Lines, "branch" => Fact::Branch,
+            "hour" => Fact::Hour, "weekday" => Fact::Weekday, "new" => Fact::New, "tracked" => Fact::Tracked,
+            _ => return None,
+        })
+    }
+
+    fn ty(self) -> Type {
+        match self {
+            Fact::Size | Fact::Lines | Fact::Hour => Type::Number,
+            Fact::Kind | Fact::Branch | Fact::Weekday => Type::Text,
+            Fact::New | Fact::Tracked => Type::Bool,
+        }
+    }
+}
+
+impl fmt::Display for Fact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&format!("{:?}", self).to_lowercase())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token { Ident(String), Number(u64), Text(String), Op(&'static str), Open, Close }
+
+fn tokenize(src: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = src.char_indices().peekable();
+    while let Some(&(i, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => { chars.next(); }
+            '(' => { chars.next(); tokens.push(Token::Open); }
+            ')' => { chars.next(); tokens.push(Token::Close); }
+            '"' | '\'' => {
+                chars.next();
+                let end = src[i + 1..].find(c).ok_or_else(|| format!("unterminated string at {}", i))?;
+                tokens.push(Token::Text(src[i + 1..i + 1 + end].to_string()));
+                while chars.peek().is_some_and(|&(j, _)| j <= i + 1 + end) { chars.next(); }
+            }
+            _ => {
+                let rest = &src[i..];
+                if let Some(op) = ["==", "!=", "<=", ">=", "&&", "||", "<", ">", "!"].into_iter().find(|op| rest.starts_with(op)) {
+                    for _ in 0..op.len() { chars.next(); }
+                    tokens.push(Token::Op(op));
+                    continue;
+                }
+                let word: String = rest.chars().take_while(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '/' | '.' | ':' | '*')).collect();
+                if word.is_empty() { return Err(format!("unexpected `{}`", c)); }
+                for _ in word.chars() { chars.next(); }
+                tokens.push(match size(&word) {
+                    Some(n) => Token::Number(n),
+                    None => Token::Ident(word),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// `42`, `10k`/`10kb`, `2m`/`2mb`, `1g`/`1gb` (binary multiples)
+fn size(word: &str) -> Option<u64> {
+    let digits = word.find(|c: char| !c.is_ascii_digit()).unwrap_or(word.len());
+    if digits == 0 { return None; }
+    let n: u64 = word[..digits].parse().ok()?;
+    let unit = match word[digits..].to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        _ => return None,
+    };
+    n.checked_mul(unit)
+}
+
+struct Parser { tokens: Vec<Token>, pos: usize }
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> { self.tokens.get(self.pos) }
+    fn next(&mut self) -> Option<Token> { let t = self.tokens.get(self.pos).cloned(); self.pos += 1; t }
+    fn eat(&mut self, ops: [&str; 2]) -> bool {
+        let hit = match self.peek() {
+            Some(Token::Op(op)) => ops.contains(op),
+            Some(Token::Ident(word)) => ops.contains(&word.as_str()),
+            _ => false,
+        };
+        if hit { self.pos += 1; }
+        hit
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut left = self.and()?;
+        while self.eat(["||", "or"]) { left = Expr::Or(Box::new(left), Box::new(self.and()?)); }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while self.eat(["&&", "and"]) { left = Expr::And(Box::new(left), Box::new(self.unary()?)); }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat(["!", "not"]) { return Ok(Expr::Not(Box::new(self.unary()?))); }
+        match self.next() {
+            Some(Token::Open) => {
+                let e = self.expr()?;
+                if self.next() != Some(Token::Close) { return Err("missing `)`".into()); }
+                Ok(e)
+            }
+            Some(Token::Ident(name)) => {
+                let fact = Fact::parse(&name).ok_or_else(|| format!("unknown fact `{}` (known: kind, size, lines, branch, hour, weekday, new, tracked)", name))?;
+                let op = match self.peek() {
+                    Some(Token::Op(op)) if *op != "&&" && *op != "||" && *op != "!" => Some(*op),
+                    Some(Token::Ident(word)) if word == "matches" => Some("matches"),
+                    _ => None,
+                };
+                let Some(op) = op else {
+                    return match fact.ty() {
+                        Type::Bool => Ok(Expr::Flag(fact)),
+                        _ => Err(format!("`{}` needs a comparison, e.g. `{} == ...`", fact, fact)),
+                    };
+                };
+                self.pos += 1;
+                let op = match op { "==" => Op::Eq, "!=" => Op::Ne, "<" => Op::Lt, "<=" => Op::Le, ">" => Op::Gt, ">=" => Op::Ge, _ => Op::Matches };
+                let value = self.next().ok_or_else(|| format!("missing value after `{}`", fact))?;
+                let value = match (fact.ty(), op, value) {
+                    (Type::Number, Op::Matches, _) => return Err(format!("`matches` needs a text fact, not `{}`", fact)),
+                    (Type::Number, _, Token::Number(n)) => Value::Number(n),
+                    (Type::Number, _, _) => return Err(format!("`{}` is compared with a number", fact)),
+                    (Type::Text, Op::Matches, Token::Text(t) | Token::Ident(t)) => Value::Glob(Glob::new(&t).map_err(|e| e.to_string())?.compile_matcher()),
+                    (Type::Text, Op::Eq | Op::Ne, Token::Text(t) | Token::Ident(t)) => Value::Text(t),
+                    (Type::Text, Op::Eq | Op::Ne, Token::Number(n)) => Value::Text(n.to_string()),
+                    (Type::Text, _, _) => return Err(format!("`{}` only supports ==, != and matches", fact)),
+                    (Type::Bool, _, _) => return Err(format!("`{}` is a flag; use `{}` or `!{}`", fact, fact, fact)),
+                };
+                Ok(Expr::Compare(fact, op, value))
+            }
+            Some(t) => Err(format!("unexpected {:?}", t)),
+            None => Err("unexpected end of condition".into()),
+        }
+    }
+}
+
+/// Parse one condition.
+pub fn parse(src: &str) -> Result<Expr, String> {
+    let mut p = Parser { tokens: tokenize(src)?, pos: 0 };
+    let e = p.expr()?;
+    match p.peek() {
+        None => Ok(e),
+        Some(t) => Err(format!("unexpected {:?} after condition", t)),
+    }
+}
+
+impl Expr {
+    /// Whether `fact` appears anywhere in this condition.
+    pub fn uses(&self, fact: Fact) -> bool {
+        match self {
+            Expr::And(a, b) | Expr::Or(a, b) => a.uses(fact) || b.uses(fact),
+            Expr::Not(e) => e.uses(fact),
+            Expr::Flag(f) | Expr::Compare(f, _, _) => *f == fact,
+        }
+    }
+
+    /// Whether this holds for `facts`. A comparison on a fact that is not
+    /// known (e.g. `lines` of a binary file) is false.
+    pub fn eval(&self, facts: &Facts) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(facts) && b.eval(facts),
+            Expr::Or(a, b) => a.eval(facts) || b.eval(facts),
+            Expr::Not(e) => !e.eval(facts),
+            Expr::Flag(Fact::New) => facts.is_new(),
+            Expr::Flag(Fact::Tracked) => facts.tracked(),
+            Expr::Flag(_) => false,
+            Expr::Compare(fact, op, value) => {
+                let number = match fact {
+                    Fact::Size => facts.size(),
+                    Fact::Lines => facts.lines(),
+                    Fact::Hour => Some(facts.hour()),
+                    _ => None,
+                };
+                match (number, value) {
+                    (Some(n), Value::Number(v)) => match op {
+                        Op::Eq => n == *v, Op::Ne => n != *v, Op::Lt => n < *v, Op::Le => n <= *v, Op::Gt => n > *v, Op::Ge => n >= *v,
+                        Op::Matches => false,
+                    },
+                    (None, Value::Number(_)) => false,
+                    (_, text) => {
+                        let Some(actual) = (match fact {
+                            Fact::Kind => Some(facts.change.to_string()),
+                            Fact::Branch => facts.branch(),
+                            Fact::Weekday => Some(facts.weekday()),
+                            _ => None,
+                        }) else { return false };
+                        match (op, text) {
+                            (Op::Eq, Value::Text(t)) => actual.eq_ignore_ascii_case(t),
+                            (Op::Ne, Value::Text(t)) => !actual.eq_ignore_ascii_case(t),
+                            (Op::Matches, Value::Glob(g)) => g.is_match(&actual),
+                            _ => false,
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persona::Change;
+    use chrono::TimeZone;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_errors_are_specific() {
+        assert!(parse("size > 10k && (kind == create || !tracked)").unwrap().uses(Fact::Tracked));
+        assert!(!parse("branch == main").unwrap().uses(Fact::Tracked));
+        assert!(parse("inside-render").unwrap_err().contains("unknown fact `inside-render`"));
+        assert!(parse("lines > many").unwrap_err().contains("compared with a number"));
+        assert!(parse("new == true").unwrap_err().contains("is a flag"));
+        assert!(parse("(hour > 3").unwrap_err().contains("missing `)`"));
+    }
+
+    #[test]
+    fn test_eval_over_facts() {
+        let at = chrono::Local.with_ymd_and_hms(2024, 6, 1, 23, 30, 0).unwrap(); // a Saturday
+        let facts = Facts::new(Path::new("/nonexistent"), Path::new("a.rs"), Some("one\ntwo\nthree\n")).with_change(Change::Create).at(at);
+        let holds = |src: &str| parse(src).unwrap().eval(&facts);
+        assert!(holds("lines == 3 && size < 1k"));
+        assert!(holds("new && kind == create"));
+        assert!(holds("hour >= 22 or hour < 6"));
+        assert!(holds("weekday == sat"));
+        assert!(!holds("tracked"));
+        // no git repo, so no branch: comparisons on it are false either way
+        assert!(!holds("branch == main") && !holds("branch != main"));
+        // the watcher passes the branch and tracking in rather than asking git
+        let known = Facts::new(Path::new("/nonexistent"), Path::new("a.rs"), None).on_branch(Some("main".into())).with_tracked(true);
+        assert!(parse("branch == main && tracked").unwrap().eval(&known));
+    }
+}
@@ -1,44 +1,781 @@
+use crate::{condition::{self, Expr}, persona::Change, schedule::{self, Schedule}, severity::Severity, trigger::{self, Rule, Trigger}};
 use globset::{Glob, GlobSetBuilder};
-use serde::Deserialize;
-use anyhow::{Context, Result};
-use std::{collections::HashMap, path::Path};
+use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use std::{collections::{BTreeMap, HashMap}, fmt, path::{Path, PathBuf}};
+use yaml_rust2::{parser::{Event, MarkedEventReceiver, Parser}, scanner::Marker};
 
 #[derive(Debug, Deserialize, Clone)]
-pub struct ValveConfig { pub personas: HashMap<String, PersonaConfig> }
+#[serde(deny_unknown_fields)]
+pub struct ValveConfig {
+    #[serde(default)]
+    pub personas: HashMap<String, PersonaConfig>,
+    /// Persona packs whose personas this config takes in (see [`find_pack`])
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Builtin catalog version for unpinned `use:` (default 1)
+    pub catalog: Option<u32>,
+}
 
 #[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct PersonaConfig {
     pub filters: Option<Vec<String>>,      // globs
-    pub triggers: Option<Vec<String>>,     // regex
+    /// Regexes, or `all`/`any`/`none` groups of them (see [`crate::trigger`])
+    pub triggers: Option<Trigger>,
     pub response: Option<String>,          // label
-    pub severity: Option<String>,          // e.g., HALT_EVERYTHING
-    pub schedule: Option<String>,          // future use
+    pub severity: Option<Severity>,        // e.g., HALT_EVERYTHING
+    /// Predicates over the change, all of which must hold (see [`crate::condition`])
+    pub conditions: Option<Vec<String>>,
+    /// Kinds of change it reacts to; all of them when unset
+    pub on: Option<Vec<Change>>,
+    /// Sweep on a timer instead of reacting to changes (see [`crate::schedule`])
+    pub schedule: Option<String>,
+    /// How this combines with a same-named persona from an outer layer
+    #[serde(default)]
+    pub merge: Merge,
+    /// Persona whose fields this one inherits where it leaves them unset
+    pub extends: Option<String>,
+    /// Like `extends`, but from the builtin catalog: `builtin/<name>[@<version>]`
+    #[serde(rename = "use")]
+    pub uses: Option<String>,
+    /// Only a base for `extends`; never runs itself
+    #[serde(default)]
+    pub template: bool,
+    #[serde(skip)]
+    pub origin: Origin,
+}
+
+/// Where a persona and each of its fields came from, for `config explain`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Origin {
+    /// File that defines the persona
+    pub file: PathBuf,
+    /// Files whose `include:` brought it in, outermost first
+    pub included_via: Vec<PathBuf>,
+    /// Personas it extends, nearest first
+    pub extends: Vec<String>,
+    /// Field -> `persona (file)` that set it
+    pub fields: BTreeMap<&'static str, String>,
+}
+
+impl PersonaConfig {
+    /// Record this persona as the source of every field it sets.
+    fn claim_fields(&mut self, name: &str) {
+        let from = format!("{} ({})", name, self.origin.file.display());
+        let set = [("filters", self.filters.is_some()), ("triggers", self.triggers.is_some()), ("response", self.response.is_some()), ("severity", self.severity.is_some()), ("conditions", self.conditions.is_some()), ("on", self.on.is_some()), ("schedule", self.schedule.is_some())];
+        for (field, _) in set.into_iter().filter(|(_, set)| *set) { self.origin.fields.insert(field, from.clone()); }
+    }
+
+    /// Take every field left unset from `base` (named `base_name`), which this extends.
+    fn inherit(&mut self, base: &PersonaConfig, base_name: &str) {
+        macro_rules! inherit {
+            ($($field:ident),*) => { $(
+                if self.$field.is_none() {
+                    self.$field = base.$field.clone();
+                    if let Some(o) = base.origin.fields.get(stringify!($field)) { self.origin.fields.insert(stringify!($field), o.clone()); }
+                }
+            )* };
+        }
+        inherit!(filters, triggers, response, severity, conditions, on, schedule);
+        self.origin.extends = std::iter::once(base_name.to_string()).chain(base.origin.extends.iter().cloned()).collect();
+    }
+}
+
+/// What a persona does to one of the same name from an outer layer.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Merge {
+    /// Replace it
+    #[default]
+    Override,
+    /// Add filters and triggers to it; `response`/`severity` replace if set
+    Extend,
+    /// Turn it off (other fields are ignored)
+    Disable,
 }
 
 impl ValveConfig {
+    /// The repo layer alone; the daemon goes through [`layers`].
+    #[cfg(test)]
     pub fn load_from_repo(repo: &Path) -> Result<Self> {
-        let path = repo.join(".sage/valve.yml");
-        let raw = std::fs::read_to_string(&path).with_context(|| format!("missing config at {}", path.display()))?;
-        let cfg: ValveConfig = serde_yaml::from_str(&raw)?; Ok(cfg)
+        Self::load_from_file(&repo.join(".sage/valve.yml"), None)
+    }
+
+    /// Load `path` with everything it includes, resolve `extends` and drop
+    /// templates. Packs are also looked up next to the `user` config.
+    pub fn load_from_file(path: &Path, user: Option<&Path>) -> Result<Self> {
+        let mut cfg = Self::load_with_includes(path, user, &mut vec![])?;
+        cfg.personas = resolve_extends(&cfg.personas, cfg.catalog.unwrap_or(1))?;
+        Ok(cfg)
+    }
+
+    /// `path` plus the personas of the packs it includes, which its own
+    /// override by name. `stack` holds the files being loaded, for cycles.
+    fn load_with_includes(path: &Path, user: Option<&Path>, stack: &mut Vec<PathBuf>) -> Result<Self> {
+        let raw = std::fs::read_to_string(path).with_context(|| format!("missing config at {}", path.display()))?;
+        let invalid = |diagnostics| Invalid { path: path.to_path_buf(), diagnostics };
+        let mut cfg = check(&raw).map_err(|d| Invalids(vec![invalid(d)]))?;
+        let spans = Spans::index(&raw).map_err(|d| Invalids(vec![invalid(vec![d])]))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+
+        stack.push(path.canonicalize()?);
+        let mut personas = HashMap::new();
+        let mut diagnostics = vec![];
+        // keep going past a bad pack, so every file with problems is reported
+        let mut nested: Vec<Invalid> = vec![];
+        for (i, entry) in cfg.include.iter().enumerate() {
+            let field = format!("include[{i}]");
+            let (line, column) = spans.locate(&field);
+            let mut report = |message| diagnostics.push(Diagnostic { line, column, persona: None, field: Some(field.clone()), message });
+            let Some(pack) = find_pack(entry, dir, user) else {
+                report(format!("no pack `{}` (give a path relative to this file, or add packs/{}.yml here or next to the user config)", entry, entry));
+                continue;
+            };
+            let pack = pack.canonicalize()?;
+            if let Some(start) = stack.iter().position(|p| *p == pack) {
+                let cycle: Vec<String> = stack[start..].iter().chain([&pack]).map(|p| p.display().to_string()).collect();
+                report(format!("include cycle: {}", cycle.join(" -> ")));
+                continue;
+            }
+            let included = match Self::load_with_includes(&pack, user, stack) {
+                Ok(included) => included,
+                Err(e) => {
+                    let Invalids(found) = e.downcast::<Invalids>()?;
+                    for f in found { if !nested.iter().any(|n| n.path == f.path) { nested.push(f); } }
+                    continue;
+                }
+            };
+            for (name, mut p) in included.personas {
+                p.origin.included_via.insert(0, path.to_path_buf());
+                personas.insert(name, p);
+            }
+        }
+        stack.pop();
+        if !diagnostics.is_empty() { nested.insert(0, invalid(diagnostics)); }
+        if !nested.is_empty() { return Err(Invalids(nested).into()); }
+
+        for (name, mut p) in cfg.personas.drain() {
+            p.origin.file = path.to_path_buf();
+            personas.insert(name, p);
+        }
+        cfg.personas = personas;
+        Ok(cfg)
+    }
+}
+
+/// Resolve an `include:` entry: a path relative to the including file's
+/// directory, or a pack name looked up as `packs/<name>.yml` in that
+/// directory and then next to the `user` config.
+pub fn find_pack(entry: &str, dir: &Path, user: Option<&Path>) -> Option<PathBuf> {
+    if entry.contains('/') || entry.ends_with(".yml") || entry.ends_with(".yaml") {
+        return Some(dir.join(entry)).filter(|p| p.is_file());
+    }
+    let user_packs = user.and_then(|u| u.parent().map(|d| d.join("packs")));
+    [Some(dir.join("packs")), user_packs].into_iter().flatten()
+        .map(|d| d.join(format!("{}.yml", entry)))
+        .find(|p| p.is_file())
+}
+
+/// Fill in each persona's `extends` and `use` chain and drop templates.
+/// Unpinned `use:` references resolve in catalog `catalog`. A missing base
+/// or a cycle is reported at the `extends` or `use` that causes it, in
+/// whichever file that is.
+fn resolve_extends(all: &HashMap<String, PersonaConfig>, catalog: u32) -> Result<HashMap<String, PersonaConfig>> {
+    /// Err is (persona, field, why) for the reference that is bad
+    type Bad = (String, &'static str, String);
+    fn resolve(name: &str, all: &HashMap<String, PersonaConfig>, catalog: u32, done: &mut HashMap<String, PersonaConfig>, stack: &mut Vec<String>) -> std::result::Result<PersonaConfig, Bad> {
+        if let Some(p) = done.get(name) { return Ok(p.clone()); }
+        let mut p = all[name].clone();
+        p.claim_fields(name);
+        match (p.extends.clone(), p.uses.clone()) {
+            (Some(_), Some(_)) => return Err((name.to_string(), "use", "`use` and `extends` cannot be combined; extend a persona that uses the builtin".into())),
+            (Some(base), None) => {
+                stack.push(name.to_string());
+                if let Some(start) = stack.iter().position(|n| *n == base) {
+                    return Err((name.to_string(), "extends", format!("extends cycle: {} -> {}", stack[start..].join(" -> "), base)));
+                }
+                if !all.contains_key(&base) { return Err((name.to_string(), "extends", format!("extends unknown persona `{}`", base))); }
+                let parent = resolve(&base, all, catalog, done, stack)?;
+                stack.pop();
+                p.inherit(&parent, &base);
+            }
+            (None, Some(reference)) => {
+                let (pinned, mut builtin) = crate::catalog::lookup(&reference, catalog).map_err(|e| (name.to_string(), "use", e.to_string()))?;
+                builtin.claim_fields(&pinned);
+                p.inherit(&builtin, &pinned);
+            }
+            (None, None) => {}
+        }
+        done.insert(name.to_string(), p.clone());
+        Ok(p)
+    }
+
+    let mut names: Vec<&String> = all.keys().collect();
+    names.sort();
+    let mut done = HashMap::new();
+    let mut bad: BTreeMap<String, (&'static str, String)> = BTreeMap::new();
+    for name in names {
+        if let Err((culprit, field, message)) = resolve(name, all, catalog, &mut done, &mut vec![]) { bad.entry(culprit).or_insert((field, message)); }
+    }
+    if !bad.is_empty() {
+        let mut files: Vec<Invalid> = vec![];
+        for (name, (field, message)) in bad {
+            let file = &all[&name].origin.file;
+            let at = match files.iter().position(|f| f.path == *file) {
+                Some(at) => at,
+                None => { files.push(Invalid { path: file.clone(), diagnostics: vec![] }); files.len() - 1 }
+            };
+            let spans = std::fs::read_to_string(file).ok().and_then(|raw| Spans::index(&raw).ok());
+            let (line, column) = spans.as_ref().map_or((1, 1), |s| s.locate(&format!("personas.{name}.{field}")));
+            files[at].diagnostics.push(Diagnostic { line, column, persona: Some(name), field: Some(field.into()), message });
+        }
+        return Err(Invalids(files).into());
+    }
+    Ok(done.into_iter().filter(|(_, p)| !p.template).collect())
+}
+
+/// One problem in a persona config, located in its source.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub persona: Option<String>,
+    /// Path within the persona, e.g. `filters[1]`
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.column)?;
+        match (&self.persona, &self.field) {
+            (Some(p), Some(field)) => write!(f, "persona `{}` `{}`: ", p, field)?,
+            (Some(p), None) => write!(f, "persona `{}`: ", p)?,
+            (None, Some(field)) => write!(f, "`{}`: ", field)?,
+            (None, None) => {}
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// A config file that failed [`check`].
+#[derive(Debug, thiserror::Error)]
+pub struct Invalid { pub path: PathBuf, pub diagnostics: Vec<Diagnostic> }
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let n = self.diagnostics.len();
+        write!(f, "{} error{} in {}", n, if n == 1 { "" } else { "s" }, self.path.display())?;
+        for d in &self.diagnostics { write!(f, "\n  {}:{}", self.path.display(), d)?; }
+        Ok(())
+    }
+}
+
+/// Every file with problems in one load: the config, the packs it
+/// includes and whichever files the bad `extends` or `use` entries are in.
+#[derive(Debug, thiserror::Error)]
+pub struct Invalids(pub Vec<Invalid>);
+
+impl fmt::Display for Invalids {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, invalid) in self.0.iter().enumerate() {
+            if i > 0 { writeln!(f)?; }
+            write!(f, "{}", invalid)?;
+        }
+        Ok(())
+    }
+}
+
+/// The top level, with personas left raw so each can fail on its own.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Document {
+    #[serde(default)]
+    personas: serde_yaml::Mapping,
+    #[serde(default)]
+    include: Vec<String>,
+    catalog: Option<u32>,
+}
+
+/// Parse and validate a `valve.yml`: YAML syntax, unknown keys, bad values,
+/// globs and regexes. Every problem is reported, in source order; the
+/// daemon and `config check` both go through here.
+pub fn check(raw: &str) -> std::result::Result<ValveConfig, Vec<Diagnostic>> {
+    let spans = Spans::index(raw).map_err(|d| vec![d])?;
+    let located = |path: &str, persona: Option<&str>, field: Option<String>, message: String| {
+        let (line, column) = spans.locate(path);
+        Diagnostic { line, column, persona: persona.map(str::to_string), field, message }
+    };
+    let value: serde_yaml::Value = serde_yaml::from_str(raw).map_err(|e| vec![located("", None, None, e.to_string())])?;
+    let doc: Document = serde_path_to_error::deserialize(value).map_err(|e| vec![located(&e.path().to_string(), None, None, e.inner().to_string())])?;
+
+    let mut personas = HashMap::new();
+    let mut diagnostics = vec![];
+    for (key, value) in doc.personas {
+        let Some(name) = key.as_str() else {
+            diagnostics.push(located("personas", None, None, format!("persona names must be strings, found {:?}", key)));
+            continue;
+        };
+        // drop each field that fails and retry, so one typo does not hide the rest
+        let mut value = value;
+        loop {
+            match serde_path_to_error::deserialize::<_, PersonaConfig>(value.clone()) {
+                Ok(p) => {
+                    match compile_persona(name, &p) {
+                        Ok(_) => { personas.insert(name.to_string(), p); }
+                        Err(errors) => diagnostics.extend(errors.into_iter().map(|(field, message)| located(&format!("personas.{name}.{field}"), Some(name), Some(field), message))),
+                    }
+                    break;
+                }
+                Err(e) => {
+                    // the path is "." when the persona itself has the wrong shape
+                    let field = Some(e.path().to_string()).filter(|f| f != ".");
+                    let path = field.as_ref().map_or(format!("personas.{name}"), |f| format!("personas.{name}.{f}"));
+                    diagnostics.push(located(&path, Some(name), field.clone(), e.inner().to_string()));
+                    let top = field.as_deref().and_then(|f| f.split(['.', '[']).next());
+                    let removed = match (top, value.as_mapping_mut()) {
+                        (Some(top), Some(m)) => m.remove(top).is_some(),
+                        _ => false,
+                    };
+                    if !removed { break; }
+                }
+            }
+        }
+    }
+    if let Some(v) = doc.catalog.filter(|v| !(1..=crate::catalog::LATEST).contains(v)) {
+        diagnostics.push(located("catalog", None, Some("catalog".into()), format!("no builtin catalog v{} (this build has v1 to v{})", v, crate::catalog::LATEST)));
+    }
+    if !diagnostics.is_empty() {
+        diagnostics.sort_by_key(|d| (d.line, d.column));
+        return Err(diagnostics);
+    }
+    Ok(ValveConfig { personas, include: doc.include, catalog: doc.catalog })
+}
+
+/// `config check`: print every problem in each config (every layer of a
+/// repo, or a single file) and fail if there were any.
+pub fn check_files(paths: &[PathBuf], json: bool, user: Option<&Path>) -> Result<()> {
+    let mut files = vec![];
+    for path in paths {
+        if !path.is_dir() { files.push(path.clone()); continue; }
+        let found = layers(path, &path.join(".sage/valve.yml"), user);
+        if found.is_empty() { bail!("missing config at {}", path.join(".sage/valve.yml").display()); }
+        files.extend(found.into_iter().map(|l| l.file));
+    }
+    let mut errors = 0;
+    let mut reported = std::collections::HashSet::new();
+    for file in files {
+        // includes and `extends` are checked too, so a problem may be in another file
+        let Err(e) = ValveConfig::load_from_file(&file, user) else { continue };
+        let Invalids(found) = e.downcast::<Invalids>()?;
+        // a pack shared by several layers is reported once
+        for Invalid { path: file, diagnostics } in found.into_iter().filter(|f| reported.insert(f.path.clone())) {
+            errors += diagnostics.len();
+            for d in diagnostics {
+                if json {
+                    let mut v = serde_json::to_value(&d)?;
+                    v["file"] = serde_json::json!(file);
+                    println!("{}", v);
+                } else {
+                    println!("{}:{}", file.display(), d);
+                }
+            }
+        }
+    }
+    if errors > 0 { bail!("{} error{} found", errors, if errors == 1 { "" } else { "s" }); }
+    Ok(())
+}
+
+/// Source positions (1-based line and column) of every mapping key and
+/// sequence item, by path: `personas.Rust.filters[1]`.
+pub(crate) struct Spans(HashMap<String, (usize, usize)>);
+
+impl Spans {
+    pub(crate) fn index(raw: &str) -> std::result::Result<Self, Diagnostic> {
+        let mut b = SpanBuilder::default();
+        Parser::new_from_str(raw).load(&mut b, false).map_err(|e| Diagnostic {
+            line: e.marker().line(), column: e.marker().col() + 1, persona: None, field: None, message: e.info().to_string(),
+        })?;
+        Ok(Self(b.spans))
+    }
+
+    /// Where `path` starts, or its nearest ancestor that could be located.
+    pub(crate) fn locate(&self, mut path: &str) -> (usize, usize) {
+        loop {
+            if let Some(&at) = self.0.get(path) { return at; }
+            match path.rfind(['.', '[']) {
+                Some(i) => path = &path[..i],
+                None => return (1, 1),
+            }
+        }
+    }
+}
+
+enum Frame { Map { path: String, key: Option<String> }, Seq { path: String, next: usize } }
+
+#[derive(Default)]
+struct SpanBuilder { stack: Vec<Frame>, spans: HashMap<String, (usize, usize)> }
+
+impl MarkedEventReceiver for SpanBuilder {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        let at = (mark.line(), mark.col() + 1);
+        let join = |parent: &str, key: &str| if parent.is_empty() { key.to_string() } else { format!("{parent}.{key}") };
+        match ev {
+            Event::MappingEnd | Event::SequenceEnd => { self.stack.pop(); return; }
+            Event::Scalar(..) | Event::Alias(_) | Event::MappingStart(..) | Event::SequenceStart(..) => {}
+            _ => return,
+        }
+        // the path of the node starting here
+        let path = match self.stack.last_mut() {
+            None => String::new(),
+            Some(Frame::Seq { path, next }) => { *next += 1; format!("{}[{}]", path, *next - 1) }
+            Some(Frame::Map { path, key }) => match key.take() {
+                Some(k) => join(path, &k),
+                None => {
+                    // a key: remember where it is and what its value will be called
+                    let k = match &ev { Event::Scalar(k, ..) => k.clone(), _ => "?".into() };
+                    self.spans.entry(join(path, &k)).or_insert(at);
+                    *key = Some(k);
+                    if let Event::Scalar(..) | Event::Alias(_) = ev { return; }
+                    "?".into()
+                }
+            },
+        };
+        // values are located by their key, which comes first
+        self.spans.entry(path.clone()).or_insert(at);
+        match ev {
+            Event::MappingStart(..) => self.stack.push(Frame::Map { path, key: None }),
+            Event::SequenceStart(..) => self.stack.push(Frame::Seq { path, next: 0 }),
+            _ => {}
+        }
     }
 }
 
 #[derive(Clone)]
-pub struct CompiledPersona { pub name: String, pub globset: globset::GlobSet, pub triggers: Vec<regex::Regex>, pub response: Option<String>, pub severity: Option<String> }
+pub struct CompiledPersona {
+    pub name: String,
+    pub globset: globset::GlobSet,
+    /// Regexes that can make it fire (not those under `none:`), for highlighting
+    pub triggers: Vec<regex::Regex>,
+    pub rule: Option<Rule>,
+    pub response: Option<String>,
+    pub severity: Severity,
+    pub conditions: Vec<Expr>,
+    pub on: Vec<Change>,
+    pub schedule: Option<Schedule>,
+    /// Name of the layer that defined it (see [`Layer::name`])
+    pub layer: String,
+    /// Repo-relative subtree it applies to; empty for the whole repo
+    pub scope: PathBuf,
+    /// Subtrees where an inner layer overrides or disables it
+    pub shadowed: Vec<PathBuf>,
+}
 
+impl CompiledPersona {
+    /// Whether this persona is in effect for the repo-relative `rel`.
+    pub fn applies_to(&self, rel: &Path) -> bool {
+        rel.starts_with(&self.scope) && !self.shadowed.iter().any(|s| rel.starts_with(s))
+    }
+}
+
+/// Compile one persona, or list each bad field as `(field, message)`.
+fn compile_persona(name: &str, p: &PersonaConfig) -> std::result::Result<CompiledPersona, Vec<(String, String)>> {
+    let mut errors = vec![];
+    let mut b = GlobSetBuilder::new();
+    for (i, g) in p.filters.iter().flatten().enumerate() {
+        match Glob::new(g) {
+            Ok(g) => { b.add(g); }
+            Err(e) => errors.push((format!("filters[{i}]"), e.kind().to_string())),
+        }
+    }
+    let mut trigs = Vec::new();
+    let rule = match &p.triggers {
+        // an empty list leaves the globs to decide
+        None => None,
+        Some(Trigger::List(items)) if items.is_empty() => None,
+        Some(t) => trigger::compile(t, "triggers", &mut trigs, &mut errors),
+    };
+    let mut conditions = Vec::new();
+    for (i, c) in p.conditions.iter().flatten().enumerate() {
+        match condition::parse(c) {
+            Ok(c) => conditions.push(c),
+            Err(e) => errors.push((format!("conditions[{i}]"), e)),
+        }
+    }
+    if p.on.as_ref().is_some_and(|on| on.is_empty()) { errors.push(("on".into(), "lists no kinds of change, so the persona never fires".into())); }
+    let schedule = match p.schedule.as_deref().map(schedule::parse).transpose() {
+        Ok(s) => s,
+        Err(e) => { errors.push(("schedule".into(), e)); None }
+    };
+    if errors.is_empty() {
+        match b.build() {
+            Ok(globset) => return Ok(CompiledPersona {
+                name: name.to_string(), globset, triggers: trigs, rule, response: p.response.clone(), severity: p.severity.unwrap_or_default(), conditions, schedule,
+                on: p.on.clone().unwrap_or_else(|| if p.triggers.is_some() { Change::DEFAULT_ON_TRIGGERS } else { Change::DEFAULT_ON }.to_vec()),
+                layer: String::new(), scope: PathBuf::new(), shadowed: vec![],
+            }),
+            Err(e) => errors.push(("filters".into(), e.to_string())),
+        }
+    }
+    Err(errors)
+}
+
+/// Compile a single config, ignoring layering.
+#[cfg(test)]
 pub fn compile(cfg: &ValveConfig) -> Result<Vec<CompiledPersona>> {
     let mut v = Vec::new();
+    let mut errors = Vec::new();
     for (name, p) in &cfg.personas {
-        let mut b = GlobSetBuilder::new();
-        for g in p.filters.clone().unwrap_or_default() { b.add(Glob::new(&g)?); }
-        let gs = b.build()?;
-        let mut trigs = Vec::new();
-        for r in p.triggers.clone().unwrap_or_default() { trigs.push(regex::Regex::new(&r)?); }
-        v.push(CompiledPersona { name: name.clone(), globset: gs, triggers: trigs, response: p.response.clone(), severity: p.severity.clone() });
+        match compile_persona(name, p) {
+            Ok(c) => v.push(c),
+            Err(e) => errors.extend(e.into_iter().map(|(field, message)| format!("persona `{}` `{}`: {}", name, field, message))),
+        }
     }
+    if !errors.is_empty() { bail!(errors.join("; ")); }
     Ok(v)
 }
 
+/// One config file in the stack that applies to a repo.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    /// Recorded on events: `user`, or the file relative to the repo
+    pub name: String,
+    pub file: PathBuf,
+    /// Repo-relative directory the layer covers; empty for user and repo layers
+    pub scope: PathBuf,
+}
+
+/// The user-level config shared by every codebase: `$SAGE_VALVE_USER_CONFIG`
+/// if set (empty turns it off), else [`default_user_config`]. The daemon
+/// takes it from its settings instead.
+pub fn user_config() -> Option<PathBuf> {
+    match std::env::var_os("SAGE_VALVE_USER_CONFIG") {
+        Some(p) if p.is_empty() => None,
+        Some(p) => Some(p.into()),
+        None => default_user_config(),
+    }
+}
+
+/// `~/.config/sage/valve.yml`
+pub fn default_user_config() -> Option<PathBuf> {
+    directories::BaseDirs::new().map(|d| d.home_dir().join(".config/sage/valve.yml"))
+}
+
+/// Config files for `repo`, outermost first: `user`, then `repo_config`
+/// (normally `.sage/valve.yml`), then `.sage/valve.yml` in subdirectories,
+/// shallowest first. Files that do not exist are left out, and nested git
+/// repos are not searched.
+pub fn layers(repo: &Path, repo_config: &Path, user: Option<&Path>) -> Vec<Layer> {
+    let mut found = vec![];
+    if let Some(user) = user.filter(|u| u.is_file()) {
+        found.push(Layer { name: "user".into(), file: user.to_path_buf(), scope: PathBuf::new() });
+    }
+    if repo_config.is_file() {
+        let name = repo_config.strip_prefix(repo).unwrap_or(repo_config).display().to_string();
+        found.push(Layer { name, file: repo_config.to_path_buf(), scope: PathBuf::new() });
+    }
+    let mut nested: Vec<PathBuf> = ignore::WalkBuilder::new(repo).hidden(false)
+        .filter_entry(|e| e.file_name() != ".git" && (e.depth() == 0 || !e.path().join(".git").exists()))
+        .build().flatten()
+        .filter(|e| e.depth() > 2 && e.file_name() == "valve.yml" && e.path().parent().is_some_and(|p| p.ends_with(".sage")))
+        .filter_map(|e| e.path().strip_prefix(repo).ok().map(Path::to_path_buf))
+        .collect();
+    nested.sort_by_key(|rel| (rel.components().count(), rel.clone()));
+    for rel in nested {
+        let scope = rel.parent().and_then(Path::parent).unwrap_or(Path::new("")).to_path_buf();
+        found.push(Layer { name: rel.display().to_string(), file: repo.join(&rel), scope });
+    }
+    found
+}
+
+/// A config file and its contents, `None` if it could not be read.
+pub type Input = (PathBuf, Option<String>);
+
+/// Every file the personas from `layers` are read from, with its contents:
+/// `user` and `repo_config` whether or not they exist, each layer, and the
+/// packs they include, transitively. Compare the contents to tell whether
+/// the personas are stale without walking the repo.
+pub fn inputs(repo_config: &Path, layers: &[Layer], user: Option<&Path>) -> Vec<Input> {
+    #[derive(Deserialize)]
+    struct Includes { #[serde(default)] include: Vec<String> }
+    let canonical = |p: &Path| p.canonicalize().unwrap_or_else(|_| p.to_path_buf());
+    let mut files: Vec<PathBuf> = vec![];
+    for f in user.into_iter().chain([repo_config]).chain(layers.iter().map(|l| l.file.as_path())).map(canonical) {
+        if !files.contains(&f) { files.push(f); }
+    }
+    let mut out = vec![];
+    // `files` grows as includes turn up
+    let mut i = 0;
+    while i < files.len() {
+        let raw = std::fs::read_to_string(&files[i]).ok();
+        let dir = files[i].parent().unwrap_or(Path::new(".")).to_path_buf();
+        // best effort: a config that does not parse fails when it is loaded
+        let includes = raw.as_deref().and_then(|r| serde_yaml::from_str::<Includes>(r).ok()).map(|c| c.include).unwrap_or_default();
+        for pack in includes.iter().filter_map(|e| find_pack(e, &dir, user)).map(|p| canonical(&p)) {
+            if !files.contains(&pack) { files.push(pack); }
+        }
+        out.push((files[i].clone(), raw));
+        i += 1;
+    }
+    out
+}
+
+/// A persona as it applies after merging layers.
+#[derive(Debug, Clone)]
+pub struct Effective {
+    pub name: String,
+    pub persona: PersonaConfig,
+    /// Name of the innermost layer that defined it
+    pub layer: String,
+    pub scope: PathBuf,
+    pub shadowed: Vec<PathBuf>,
+}
+
+/// Merge `layers` (as returned by [`layers`]) into one set of personas.
+/// A nested layer's globs are relative to its directory and its personas
+/// only apply below it; where it overrides or disables an outer persona,
+/// the outer one is shadowed for that subtree.
+pub fn merge_layers(layers: &[Layer], user: Option<&Path>) -> Result<Vec<Effective>> {
+    let mut entries: Vec<Effective> = vec![];
+    for layer in layers {
+        let cfg = ValveConfig::load_from_file(&layer.file, user)?;
+        let mut names: Vec<&String> = cfg.personas.keys().collect();
+        names.sort();
+        for name in names {
+            let mut p = cfg.personas[name].clone();
+            if !layer.scope.as_os_str().is_empty() {
+                let base = globset::escape(&layer.scope.to_string_lossy().replace('\\', "/"));
+                p.filters = p.filters.map(|fs| fs.iter().map(|g| format!("{}/{}", base, g.trim_start_matches('/'))).collect());
+            }
+            // the innermost same-named persona in effect at this layer's scope
+            let inherited = entries.iter().rposition(|e| e.name == *name && layer.scope.starts_with(&e.scope) && !e.shadowed.iter().any(|s| layer.scope.starts_with(s)));
+            let merged = match (p.merge, inherited) {
+                (Merge::Disable, None) => continue,
+                (Merge::Disable, Some(_)) => None,
+                (Merge::Override, _) | (Merge::Extend, None) => Some(p),
+                (Merge::Extend, Some(i)) => Some(extend_layer(&entries[i].persona, p)),
+            };
+            match inherited {
+                // same scope (user and repo layers): replace in place
+                Some(i) if entries[i].scope == layer.scope => { entries.remove(i); }
+                Some(i) => entries[i].shadowed.push(layer.scope.clone()),
+                None => {}
+            }
+            if let Some(persona) = merged {
+                entries.push(Effective { name: name.clone(), persona, layer: layer.name.clone(), scope: layer.scope.clone(), shadowed: vec![] });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// `inner` with `merge: extend` on top of `outer`: filters, triggers and
+/// conditions add up, `response` and `severity` replace if set.
+fn extend_layer(outer: &PersonaConfig, inner: PersonaConfig) -> PersonaConfig {
+    let filters = match (&outer.filters, &inner.filters) {
+        (None, None) => None,
+        // no filters means every file, which extending cannot widen
+        (None, _) => Some(vec![]),
+        (Some(a), _) if a.is_empty() => Some(vec![]),
+        (Some(a), b) => Some(a.iter().chain(b.iter().flatten()).cloned().collect()),
+    };
+    let add = |a: &Option<Vec<String>>, b: &Option<Vec<String>>| match (a, b) {
+        (None, None) => None,
+        (a, b) => Some(a.iter().chain(b.iter()).flatten().cloned().collect()),
+    };
+    let mut origin = inner.origin.clone();
+    for (field, from) in &outer.origin.fields {
+        origin.fields.entry(field).and_modify(|o| if matches!(*field, "filters" | "triggers" | "conditions" | "on") { *o = format!("{} + {}", from, o) }).or_insert_with(|| from.clone());
+    }
+    PersonaConfig {
+        filters,
+        triggers: match (&outer.triggers, inner.triggers) {
+            (Some(Trigger::List(a)), Some(Trigger::List(b))) => Some(Trigger::List(a.iter().cloned().chain(b).collect())),
+            (Some(a), Some(b)) => Some(Trigger::Any { any: vec![a.clone(), b] }),
+            (a, b) => b.or_else(|| a.clone()),
+        },
+        conditions: add(&outer.conditions, &inner.conditions),
+        response: inner.response.or_else(|| outer.response.clone()),
+        severity: inner.severity.or(outer.severity),
+        schedule: inner.schedule.or_else(|| outer.schedule.clone()),
+        on: match (&outer.on, inner.on) {
+            (Some(a), Some(b)) => Some(a.iter().copied().chain(b.into_iter().filter(|c| !a.contains(c))).collect()),
+            (a, b) => b.or_else(|| a.clone()),
+        },
+        origin,
+        ..inner
+    }
+}
+
+/// Merge and compile `layers`; see [`merge_layers`].
+pub fn compile_layers(layers: &[Layer], user: Option<&Path>) -> Result<Vec<CompiledPersona>> {
+    let mut v = Vec::new();
+    for e in merge_layers(layers, user)? {
+        let mut c = compile_persona(&e.name, &e.persona).map_err(|errors| {
+            let errors: Vec<String> = errors.into_iter().map(|(field, message)| format!("`{}`: {}", field, message)).collect();
+            anyhow::anyhow!("persona `{}` from {}: {}", e.name, e.layer, errors.join("; "))
+        })?;
+        c.layer = e.layer;
+        c.scope = e.scope;
+        c.shadowed = e.shadowed;
+        v.push(c);
+    }
+    Ok(v)
+}
+
+/// `config explain`: every persona in effect for `repo` (or just `only`),
+/// with the layer, file, includes and `extends` chain it came through and
+/// where each field was set.
+pub fn explain(repo: &Path, only: Option<&str>, user: Option<&Path>) -> Result<String> {
+    use std::fmt::Write;
+    let found = layers(repo, &repo.join(".sage/valve.yml"), user);
+    if found.is_empty() { bail!("missing config at {}", repo.join(".sage/valve.yml").display()); }
+    let mut effective = merge_layers(&found, user)?;
+    effective.retain(|e| only.is_none_or(|n| n == e.name));
+    if effective.is_empty() { bail!("no persona `{}` in effect", only.unwrap_or_default()); }
+    effective.sort_by(|a, b| (&a.scope, &a.name).cmp(&(&b.scope, &b.name)));
+
+    let mut out = String::new();
+    for e in effective {
+        let p = &e.persona;
+        let row = |out: &mut String, key: &str, value: String| { let _ = writeln!(out, "  {:<13}{}", key, value); };
+        let _ = writeln!(out, "{}", e.name);
+        row(&mut out, "layer", e.layer.clone());
+        if !e.scope.as_os_str().is_empty() { row(&mut out, "scope", e.scope.display().to_string()); }
+        if !e.shadowed.is_empty() { row(&mut out, "shadowed in", e.shadowed.iter().map(|s| s.display().to_string()).collect::<Vec<_>>().join(", ")); }
+        row(&mut out, "defined in", p.origin.file.display().to_string());
+        if !p.origin.included_via.is_empty() { row(&mut out, "included via", p.origin.included_via.iter().map(|f| f.display().to_string()).collect::<Vec<_>>().join(" -> ")); }
+        if !p.origin.extends.is_empty() { row(&mut out, "extends", p.origin.extends.join(" -> ")); }
+        let fields = [
+            ("filters", p.filters.as_ref().map(|v| serde_json::json!(v).to_string())),
+            ("triggers", p.triggers.as_ref().map(|v| serde_json::json!(v).to_string())),
+            ("conditions", p.conditions.as_ref().map(|v| serde_json::json!(v).to_string())),
+            ("on", p.on.as_ref().map(|v| serde_json::json!(v).to_string())),
+            ("schedule", p.schedule.clone()),
+            ("response", p.response.clone()),
+            ("severity", p.severity.map(|s| s.to_string())),
+        ];
+        for (field, value) in fields {
+            match (value, p.origin.fields.get(field)) {
+                (Some(v), Some(from)) => row(&mut out, field, format!("{}  # {}", v, from)),
+                (Some(v), None) => row(&mut out, field, v),
+                (None, _) if field == "severity" => row(&mut out, field, format!("{}  # default", Severity::default())),
+                (None, _) => {}
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Personas for `repo` from all its layers, with `repo_config` as the repo
+/// layer and `user` (normally [`user_config`]) as the outermost.
+pub fn load_layered(repo: &Path, repo_config: &Path, user: Option<&Path>) -> Result<Vec<CompiledPersona>> {
+    let layers = layers(repo, repo_config, user);
+    if layers.is_empty() { bail!("missing config at {}", repo_config.display()); }
+    compile_layers(&layers, user)
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs;
@@ -67,9 +804,9 @@ personas:
         assert_eq!(config.personas.len(), 1);
         let persona = config.personas.get("TestWatcher").expect("TestWatcher not found");
         assert_eq!(persona.filters.as_ref().unwrap().len(), 1);
-        assert_eq!(persona.triggers.as_ref().unwrap().len(), 1);
+        assert!(matches!(&persona.triggers, Some(Trigger::List(t)) if t.len() == 1));
         assert_eq!(persona.response.as_ref().unwrap(), "test-response");
-        assert_eq!(persona.severity.as_ref().unwrap(), "low");
+        assert_eq!(persona.severity, Some(Severity::Low));
     }
 
     #[test]
@@ -103,13 +840,33 @@ personas:
         assert_eq!(test_watcher.globset.len(), 1);
         assert_eq!(test_watcher.triggers.len(), 1);
         assert_eq!(test_watcher.response.as_ref().unwrap(), "test-response");
-        assert_eq!(test_watcher.severity.as_ref().unwrap(), "low");
+        assert_eq!(test_watcher.severity, Severity::Low);
         
         // Find the AnotherWatcher persona
         let another_watcher = compiled.iter().find(|p| p.name == "AnotherWatcher").expect("AnotherWatcher not found");
         assert_eq!(another_watcher.globset.len(), 2);
         assert_eq!(another_watcher.triggers.len(), 2);
         assert_eq!(another_watcher.response.as_ref().unwrap(), "rust-code-detected");
+        assert_eq!(another_watcher.severity, Severity::Info);
+    }
+
+    #[test]
+    fn test_unknown_severity_fails_to_load() {
+        let config_str = r#"
+personas:
+  TestWatcher:
+    filters: ["**/*.txt"]
+    severity: "catastrophic"
+"#;
+        
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let sage_dir = temp_dir.path().join(".sage");
+        std::fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        let config_file = sage_dir.join("valve.yml");
+        fs::write(&config_file, config_str).expect("Failed to write config file");
+        
+        let err = ValveConfig::load_from_repo(temp_dir.path()).expect_err("unknown severity should not load");
+        assert!(err.to_string().contains("unknown severity"));
     }
 
     #[test]
@@ -159,4 +916,178 @@ personas:
         assert!(persona.triggers[1].is_match("println!(\"Hello, world!\");"));
         assert!(!persona.triggers[0].is_match("fn test() {"));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_check_reports_every_problem_with_location() {
+        let config_str = r#"personas:
+  Rust:
+    filter: ["**/*.rs"]
+    triggers: ["unsafe", "[z-a]"]
+  Secrets:
+    filters: ["**/*.env", "src/[a.rs"]
+    triggers: ["AKIA[0-9A-Z]{16}", "(unclosed"]
+  Loud:
+    filters: ["**/*"]
+    severity: "catastrophic"
+"#;
+        let diagnostics = check(config_str).expect_err("config should not validate");
+        assert_eq!(diagnostics.iter().map(|d| (d.line, d.column, d.persona.as_deref(), d.field.as_deref())).collect::<Vec<_>>(), vec![
+            (3, 5, Some("Rust"), Some("filter")),
+            (4, 26, Some("Rust"), Some("triggers[1]")),
+            (6, 27, Some("Secrets"), Some("filters[1]")),
+            (7, 36, Some("Secrets"), Some("triggers[1]")),
+            (10, 5, Some("Loud"), Some("severity")),
+        ]);
+        assert!(diagnostics[0].message.contains("unknown field `filter`"));
+        assert!(diagnostics[4].message.contains("unknown severity"));
+    }
+
+    #[test]
+    fn test_check_reports_syntax_errors() {
+        let diagnostics = check("personas:\n  Rust:\n    filters: [\"**/*.rs\"\n").expect_err("syntax error");
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].persona.is_none());
+        assert!(diagnostics[0].line >= 3, "{}", diagnostics[0]);
+    }
+
+    #[test]
+    fn test_layers_merge_by_name_and_scope() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let repo = temp_dir.path().join("repo");
+        let write = |rel: &str, body: &str| {
+            let path = temp_dir.path().join(rel);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, body).unwrap();
+        };
+        write("user.yml", "personas:\n  Secrets:\n    filters: [\"**/*.env\"]\n  Todo:\n    triggers: [\"TODO\"]\n");
+        write("repo/.sage/valve.yml", "personas:\n  Secrets:\n    merge: extend\n    filters: [\"**/*.pem\"]\n    severity: high\n  Rust:\n    filters: [\"**/*.rs\"]\n");
+        write("repo/packages/api/.sage/valve.yml", "personas:\n  Rust:\n    filters: [\"src/*.rs\"]\n    triggers: [\"unsafe\"]\n  Todo:\n    merge: disable\n");
+
+        let found = layers(&repo, &repo.join(".sage/valve.yml"), Some(&temp_dir.path().join("user.yml")));
+        assert_eq!(found.iter().map(|l| l.name.as_str()).collect::<Vec<_>>(), vec!["user", ".sage/valve.yml", "packages/api/.sage/valve.yml"]);
+
+        let personas = compile_layers(&found, None).expect("Failed to compile layers");
+        let hits = |rel: &str, text: &str| {
+            let mut v: Vec<_> = crate::persona::match_personas(&personas, &repo, Path::new(rel), Some(text)).into_iter().map(|e| format!("{}@{}", e.persona, e.layer)).collect();
+            v.sort();
+            v
+        };
+        assert_eq!(hits("certs/key.pem", "TODO"), vec!["Secrets@.sage/valve.yml", "Todo@user"]);
+        assert_eq!(hits("main.rs", ""), vec!["Rust@.sage/valve.yml"]);
+        // the nested Rust replaces the repo one below packages/api, with globs relative to it
+        assert_eq!(hits("packages/api/src/lib.rs", "unsafe { } // TODO"), vec!["Rust@packages/api/.sage/valve.yml"]);
+        assert!(hits("packages/api/src/lib.rs", "fn safe() {}").is_empty());
+        assert!(hits("packages/api/deep/x.rs", "unsafe").is_empty());
+        assert_eq!(personas.iter().find(|p| p.name == "Secrets").unwrap().severity, Severity::High);
+    }
+
+    #[test]
+    fn test_include_and_extends_resolve_with_provenance() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let sage_dir = temp_dir.path().join(".sage");
+        fs::create_dir_all(sage_dir.join("packs")).expect("Failed to create packs directory");
+        fs::write(sage_dir.join("packs/rust.yml"), r#"
+personas:
+  RustBase:
+    template: true
+    filters: ["**/*.rs"]
+    severity: medium
+  Unsafe:
+    extends: RustBase
+    triggers: ["unsafe"]
+"#).unwrap();
+        fs::write(sage_dir.join("valve.yml"), r#"
+include: [rust]
+personas:
+  Panics:
+    extends: Unsafe
+    triggers: ["panic!"]
+    severity: high
+"#).unwrap();
+
+        let config = ValveConfig::load_from_file(&sage_dir.join("valve.yml"), None).expect("Failed to load config");
+        let mut names: Vec<_> = config.personas.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, vec!["Panics", "Unsafe"]);
+
+        let panics = &config.personas["Panics"];
+        assert_eq!(panics.filters.as_deref(), Some(&["**/*.rs".to_string()][..]));
+        assert_eq!(panics.triggers, Some(Trigger::List(vec![Trigger::Pattern("panic!".into())])));
+        assert_eq!(panics.severity, Some(Severity::High));
+        assert_eq!(panics.origin.extends, vec!["Unsafe", "RustBase"]);
+        assert!(panics.origin.fields["filters"].starts_with("RustBase ("));
+        assert_eq!(config.personas["Unsafe"].origin.included_via, vec![sage_dir.join("valve.yml")]);
+
+        let explained = explain(temp_dir.path(), Some("Panics"), None).expect("explain");
+        assert!(explained.contains("extends      Unsafe -> RustBase"), "{explained}");
+
+        // packs count as inputs, so editing one makes the personas stale
+        let found = layers(temp_dir.path(), &sage_dir.join("valve.yml"), None);
+        let files: Vec<PathBuf> = inputs(&sage_dir.join("valve.yml"), &found, None).into_iter().map(|(f, _)| f).collect();
+        assert_eq!(files, vec![sage_dir.join("valve.yml").canonicalize().unwrap(), sage_dir.join("packs/rust.yml").canonicalize().unwrap()]);
+    }
+
+    #[test]
+    fn test_extends_and_include_cycles_are_reported() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let dir = temp_dir.path();
+        fs::write(dir.join("a.yml"), "include: [b.yml]\npersonas:\n  A:\n    extends: B\n  B:\n    extends: A\n").unwrap();
+        fs::write(dir.join("b.yml"), "include: [a.yml]\n").unwrap();
+        fs::write(dir.join("c.yml"), "personas:\n  A:\n    extends: B\n  B:\n    extends: A\n  C:\n    extends: Missing\n").unwrap();
+
+        let err = ValveConfig::load_from_file(&dir.join("a.yml"), None).expect_err("include cycle");
+        let Invalids(mut found) = err.downcast::<Invalids>().unwrap();
+        assert_eq!(found.len(), 1);
+        let invalid = found.remove(0);
+        assert_eq!(invalid.path, dir.join("b.yml"));
+        assert!(invalid.diagnostics[0].message.starts_with("include cycle:"));
+        assert_eq!((invalid.diagnostics[0].line, invalid.diagnostics[0].column), (1, 11));
+        assert_eq!(inputs(&dir.join("a.yml"), &[], None).len(), 2);
+
+        let err = ValveConfig::load_from_file(&dir.join("c.yml"), None).expect_err("extends cycle");
+        let invalid = err.downcast::<Invalids>().unwrap().0.remove(0);
+        let found: Vec<_> = invalid.diagnostics.iter().map(|d| (d.line, d.persona.as_deref().unwrap(), d.message.as_str())).collect();
+        assert_eq!(found, vec![
+            (3, "A", "extends cycle: B -> A -> B"),
+            (5, "B", "extends cycle: A -> B -> A"),
+            (7, "C", "extends unknown persona `Missing`"),
+        ]);
+    }
+
+    #[test]
+    fn test_problems_in_every_file_are_reported() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let dir = temp_dir.path();
+        fs::write(dir.join("a.yml"), "include: [x.yml, y.yml, nope]\n").unwrap();
+        fs::write(dir.join("x.yml"), "personas:\n  X:\n    severity: loud\n").unwrap();
+        fs::write(dir.join("y.yml"), "personas:\n  Y:\n    filters: ['[']\n").unwrap();
+        let Invalids(found) = ValveConfig::load_from_file(&dir.join("a.yml"), None).expect_err("bad packs").downcast::<Invalids>().unwrap();
+        let files: Vec<_> = found.iter().map(|f| (f.path.file_name().unwrap().to_str().unwrap(), f.diagnostics.len())).collect();
+        assert_eq!(files, vec![("a.yml", 1), ("x.yml", 1), ("y.yml", 1)]);
+
+        // bad `extends` are reported in each file they are in
+        fs::write(dir.join("b.yml"), "include: [z.yml]\npersonas:\n  B:\n    extends: Missing\n").unwrap();
+        fs::write(dir.join("z.yml"), "personas:\n  Z:\n    extends: Gone\n").unwrap();
+        let Invalids(found) = ValveConfig::load_from_file(&dir.join("b.yml"), None).expect_err("bad extends").downcast::<Invalids>().unwrap();
+        let files: Vec<_> = found.iter().map(|f| (f.path.file_name().unwrap().to_str().unwrap(), f.diagnostics[0].persona.as_deref().unwrap())).collect();
+        assert_eq!(files, vec![("b.yml", "B"), ("z.yml", "Z")]);
+        assert!(check_files(&[dir.join("a.yml"), dir.join("b.yml")], false, None).is_err());
+    }
+
+    #[test]
+    fn test_use_builtin_with_overrides() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let file = temp_dir.path().join("valve.yml");
+        fs::write(&file, "catalog: 1\npersonas:\n  Guardian:\n    use: builtin/guardian\n    severity: high\n  Ghost:\n    use: builtin/nobody\n").unwrap();
+        let invalid = ValveConfig::load_from_file(&file, None).expect_err("unknown builtin").downcast::<Invalids>().unwrap().0.remove(0);
+        assert_eq!((invalid.diagnostics[0].line, invalid.diagnostics[0].field.as_deref()), (7, Some("use")));
+
+        fs::write(&file, "personas:\n  Guardian:\n    use: builtin/guardian@1\n    severity: high\n").unwrap();
+        let config = ValveConfig::load_from_file(&file, None).expect("Failed to load config");
+        let guardian = &config.personas["Guardian"];
+        assert_eq!(guardian.severity, Some(Severity::High));
+        assert_eq!(guardian.response.as_deref(), Some("security-paranoid"));
+        assert_eq!(guardian.origin.extends, vec!["builtin/guardian@1"]);
+        assert!(check("catalog: 99\npersonas: {}\n").is_err());
+    }
+}
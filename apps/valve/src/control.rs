@@ -1,17 +1,29 @@
-use crate::state::{Registry, SharedRegistry};
+use crate::{daemon::ReloadSummary, discover::{self, DiscoveryRoot}, severity::Severity, sink::EventBus, state::{AddOutcome, Codebase, CodebasePatch, Discovered, OverlapPolicy, SharedRegistry}, supervisor::{stopped, StatusBoard, StopSignal, WatchStatus}};
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{net::SocketAddr};
-use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}};
+use std::sync::Arc;
+use tokio::{io::{AsyncBufReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}, sync::{broadcast::error::RecvError, mpsc, oneshot, Notify}, task::JoinSet};
 use tracing::{info, warn};
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type")]
 enum Command { 
-    Register { path: String }, 
+    Register { path: String, #[serde(default)] on_overlap: OverlapPolicy }, 
+    /// Register every repo under `root`; `watch` keeps tracking it
+    Discover { #[serde(flatten)] root: DiscoveryRoot, #[serde(default)] watch: bool },
     Unregister { target: String }, 
-    List 
+    List,
+    /// What the supervisor is doing with each codebase
+    Status,
+    /// Show one codebase (by id, alias, path or id prefix)
+    Show { target: String },
+    /// Edit alias, tags, enabled flag or overrides
+    Edit { target: String, #[serde(flatten)] patch: CodebasePatch },
+    /// Re-read registry, persona configs and daemon settings (like SIGHUP)
+    Reload,
+    /// Stream valve events on this connection until it closes
+    Subscribe { #[serde(default)] min_severity: Option<Severity> },
 }
 
 #[derive(Debug, Serialize)]
@@ -19,32 +31,69 @@ enum Command {
 enum Reply { 
     Ok, 
     Error { message: String }, 
-    List { items: Vec<(String,String)> } 
+    Registered { id: String, path: String, outcome: AddOutcome },
+    Codebase { codebase: Codebase },
+    Discovered { #[serde(flatten)] result: Discovered },
+    List { items: Vec<Codebase>, roots: Vec<DiscoveryRoot> },
+    Status { items: Vec<StatusItem> },
+    Reloaded { summary: ReloadSummary },
 }
 
-pub async fn server(port: u16, reg: std::sync::Arc<Registry>, _chron: std::path::PathBuf) -> Result<()> {
-    let addr = SocketAddr::from(([127,0,0,1], port));
-    let listener = TcpListener::bind(addr).await?;
-    info!(%addr, "control listening");
+#[derive(Debug, Serialize)]
+struct StatusItem { id: String, path: String, #[serde(flatten)] status: WatchStatus }
+
+/// A reload request; the daemon answers on the enclosed channel.
+pub type ReloadRequest = oneshot::Sender<Result<ReloadSummary, String>>;
+
+/// What control sessions share with the daemon.
+#[derive(Clone)]
+pub struct Ctx {
+    pub shared: SharedRegistry,
+    pub bus: EventBus,
+    pub board: StatusBoard,
+    /// Woken after every registry mutation so the supervisor can reconcile
+    pub changed: Arc<Notify>,
+    pub reload: mpsc::UnboundedSender<ReloadRequest>,
+    /// Once set, stop accepting and close sessions
+    pub stop: StopSignal,
+}
 
-    let shared = SharedRegistry::new(reg.as_ref().clone());
+/// Serve control sessions until `ctx.stop` is set, then return once open
+/// sessions have closed.
+pub async fn server(listener: TcpListener, ctx: Ctx) -> Result<()> {
+    info!(addr = %listener.local_addr()?, "control listening");
 
+    let mut stop = ctx.stop.clone();
+    let mut sessions = JoinSet::new();
     loop {
-        let (sock, _) = listener.accept().await?;
-        let sreg = shared.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle(sock, sreg).await { 
+        let sock = tokio::select! {
+            _ = stopped(&mut stop) => break,
+            accepted = listener.accept() => accepted?.0,
+        };
+        let ctx = ctx.clone();
+        sessions.spawn(async move {
+            if let Err(e) = handle(sock, ctx).await { 
                 warn!(?e, "control session"); 
             }
         });
+        while sessions.try_join_next().is_some() {}
     }
+    drop(listener);
+    sessions.join_all().await;
+    Ok(())
 }
 
-async fn handle(sock: TcpStream, shared: SharedRegistry) -> Result<()> {
+async fn handle(sock: TcpStream, ctx: Ctx) -> Result<()> {
+    let Ctx { shared, bus, board, changed, reload, mut stop } = ctx;
     let (r, mut w) = sock.into_split();
     let mut br = BufReader::new(r);
     let mut line = String::new();
-    while br.read_line(&mut line).await? > 0 {
+    loop {
+        let read = tokio::select! {
+            _ = stopped(&mut stop) => break,
+            read = br.read_line(&mut line) => read?,
+        };
+        if read == 0 { break; }
         let cmd: Command = match serde_json::from_str(line.trim()) { 
             Ok(c) => c, 
             Err(e) => { 
@@ -57,12 +106,13 @@ async fn handle(sock: TcpStream, shared: SharedRegistry) -> Result<()> {
         };
         
         // Process the command and generate response
+        let mutates = !matches!(cmd, Command::List | Command::Status | Command::Show { .. } | Command::Reload | Command::Subscribe { .. });
         let response = match cmd {
-            Command::Register { path } => {
+            Command::Register { path, on_overlap } => {
                 let mut reg = shared.0.write();
-                match reg.add(path) { 
-                    Ok(_cb) => { 
-                        json!(Reply::Ok).to_string()
+                match reg.add(path, on_overlap) { 
+                    Ok((cb, outcome)) => { 
+                        json!(Reply::Registered{ id: cb.id, path: cb.path.to_string_lossy().to_string(), outcome }).to_string()
                     }, 
                     Err(e) => { 
                         json!(Reply::Error{ message: e.to_string() }).to_string()
@@ -75,32 +125,107 @@ async fn handle(sock: TcpStream, shared: SharedRegistry) -> Result<()> {
                     Ok(Some(_)) => {
                         json!(Reply::Ok).to_string()
                     },
-                    Ok(None) => {
-                        json!(Reply::Error{ message: "not found".into() }).to_string()
+                    // not a codebase; maybe a discovery root
+                    Ok(None) => match reg.remove_root(&target) {
+                        Ok(true) => json!(Reply::Ok).to_string(),
+                        Ok(false) => json!(Reply::Error{ message: "not found".into() }).to_string(),
+                        Err(e) => json!(Reply::Error{ message: e.to_string() }).to_string(),
                     },
                     Err(e) => {
                         json!(Reply::Error{ message: e.to_string() }).to_string()
                     },
                 }
             }
+            Command::Discover { root, watch } => {
+                // walk without holding the registry
+                let scanned = tokio::task::spawn_blocking(move || discover::scan(root)).await.unwrap_or_else(|e| Err(e.into()));
+                match scanned.and_then(|(root, found)| shared.0.write().apply_discovery(root, &found, watch)) {
+                    Ok(result) => json!(Reply::Discovered{ result }).to_string(),
+                    Err(e) => json!(Reply::Error{ message: e.to_string() }).to_string(),
+                }
+            }
             Command::List => {
                 let reg = shared.0.read();
-                let items: Vec<_> = reg.codebases.values().map(|c| (c.id.clone(), c.path.to_string_lossy().to_string())).collect();
-                json!(Reply::List{ items }).to_string()
+                let items: Vec<_> = reg.codebases.values().cloned().collect();
+                json!(Reply::List{ items, roots: reg.roots.clone() }).to_string()
+            }
+            Command::Status => {
+                let reg = shared.0.read();
+                let board = board.read();
+                let items: Vec<_> = reg.codebases.values().map(|cb| StatusItem {
+                    id: cb.id.clone(),
+                    path: cb.path.to_string_lossy().to_string(),
+                    status: if !cb.enabled { WatchStatus::Disabled } else { board.get(&cb.id).cloned().unwrap_or(WatchStatus::Starting) },
+                }).collect();
+                json!(Reply::Status{ items }).to_string()
+            }
+            Command::Show { target } => {
+                let reg = shared.0.read();
+                match reg.resolve(&target) {
+                    Some(cb) => json!(Reply::Codebase{ codebase: cb.clone() }).to_string(),
+                    None => json!(Reply::Error{ message: "not found".into() }).to_string(),
+                }
+            }
+            Command::Edit { target, patch } => {
+                let mut reg = shared.0.write();
+                match reg.edit(&target, patch) {
+                    Ok(codebase) => json!(Reply::Codebase{ codebase }).to_string(),
+                    Err(e) => json!(Reply::Error{ message: e.to_string() }).to_string(),
+                }
+            }
+            Command::Reload => {
+                let (tx, rx) = oneshot::channel();
+                let _ = reload.send(tx);
+                match rx.await {
+                    Ok(Ok(summary)) => json!(Reply::Reloaded{ summary }).to_string(),
+                    Ok(Err(message)) => json!(Reply::Error{ message }).to_string(),
+                    Err(_) => json!(Reply::Error{ message: "daemon is shutting down".into() }).to_string(),
+                }
+            }
+            Command::Subscribe { min_severity } => {
+                let mut rx = bus.subscribe();
+                let floor = min_severity.unwrap_or_default();
+                w.write_all(json!(Reply::Ok).to_string().as_bytes()).await?;
+                w.write_all(b"\n").await?;
+                loop {
+                    let recv = tokio::select! {
+                        _ = stopped(&mut stop) => break,
+                        recv = rx.recv() => recv,
+                    };
+                    match recv {
+                        Ok(ev) if ev.severity >= floor => {
+                            w.write_all((serde_json::to_string(&ev)? + "\n").as_bytes()).await?;
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(n)) => warn!(skipped = n, "subscriber lagging"),
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+                break;
             }
         };
         
+        if mutates { changed.notify_one(); }
+
         // Send the response
         w.write_all(response.as_bytes()).await?;
         w.write_all(b"\n").await?;
         line.clear();
     }
+    w.shutdown().await?;
     Ok(())
 }
 
 // Small client helpers for the CLI
-pub async fn client_register(port: u16, path: String) -> Result<()> { 
-    client_send(port, serde_json::json!({"type":"Register","path":path})).await 
+pub async fn client_register(port: u16, path: String, on_overlap: OverlapPolicy) -> Result<()> { 
+    client_send(port, serde_json::json!({"type":"Register","path":path,"on_overlap":on_overlap})).await 
+}
+
+pub async fn client_discover(port: u16, root: DiscoveryRoot, watch: bool) -> Result<()> { 
+    let mut msg = serde_json::to_value(root)?;
+    msg["type"] = json!("Discover");
+    msg["watch"] = json!(watch);
+    client_send(port, msg).await 
 }
 
 pub async fn client_unregister(port: u16, target: String) -> Result<()> { 
@@ -111,6 +236,38 @@ pub async fn client_list(port: u16) -> Result<()> {
     client_send(port, serde_json::json!({"type":"List"})).await 
 }
 
+pub async fn client_status(port: u16) -> Result<()> { 
+    client_send(port, serde_json::json!({"type":"Status"})).await 
+}
+
+pub async fn client_reload(port: u16) -> Result<()> { 
+    client_send(port, serde_json::json!({"type":"Reload"})).await 
+}
+
+pub async fn client_show(port: u16, target: String) -> Result<()> { 
+    client_send(port, serde_json::json!({"type":"Show","target":target})).await 
+}
+
+pub async fn client_edit(port: u16, target: String, patch: CodebasePatch) -> Result<()> { 
+    let mut msg = serde_json::to_value(patch)?;
+    msg["type"] = json!("Edit");
+    msg["target"] = json!(target);
+    client_send(port, msg).await 
+}
+
+pub async fn client_subscribe(port: u16, min_severity: Option<Severity>) -> Result<()> {
+    let addr = format!("127.0.0.1:{}", port);
+    let mut s = TcpStream::connect(addr).await.context("connect control")?;
+    let msg = serde_json::json!({"type":"Subscribe","min_severity":min_severity});
+    s.write_all(msg.to_string().as_bytes()).await?;
+    s.write_all(b"\n").await?;
+    let mut lines = BufReader::new(s).lines();
+    while let Some(line) = lines.next_line().await? { 
+        println!("{}", line); 
+    }
+    Ok(())
+}
+
 async fn client_send(port: u16, msg: serde_json::Value) -> Result<()> {
     let addr = format!("127.0.0.1:{}", port);
     let mut s = TcpStream::connect(addr).await.context("connect control")?;
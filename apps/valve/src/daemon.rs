@@ -1,57 +1,105 @@
-use crate::{state::Registry, control, supervisor::Supervisor};
+use crate::{state::{Discovered, Registry, SharedRegistry}, control, discover, halt::HaltStore, paths::Paths, schedule::RunLog, settings::Settings, sink::{self, NdjsonSink, Sinks}, supervisor::{Reconciled, StatusBoard, Supervisor}};
 use anyhow::{Context, Result};
-use directories::ProjectDirs;
 use fd_lock::RwLock;
-use std::{fs::File, path::PathBuf, sync::Arc};
-use tokio::signal;
-use tracing::{error, info};
+use std::{fs::File, net::SocketAddr, sync::Arc};
+use serde::Serialize;
+use serde_json::json;
+use tokio::{net::TcpListener, signal, sync::{mpsc, watch, Notify}, time::{interval, timeout_at, Duration, Instant}};
+use tracing::{error, info, warn};
 
-fn dirs() -> Result<ProjectDirs> { 
-    ProjectDirs::from("dev","sage","valve").context("dirs") 
+/// Settings that only take effect when the daemon restarts.
+const RESTART_REQUIRED: &[&str] = &["port", "discovery_interval_secs"];
+
+pub type LoadSettings = Box<dyn Fn() -> Result<Settings> + Send>;
+pub type SetLogFilter = Box<dyn Fn(&str) -> Result<()> + Send>;
+
+/// How the daemon (re-)reads its settings and swaps its log filter.
+pub struct Reloader {
+    pub settings: LoadSettings,
+    pub set_log_filter: SetLogFilter,
 }
 
-fn lockfile_path() -> Result<PathBuf> { 
-    Ok(dirs()?.runtime_dir().unwrap_or(dirs()?.data_dir()).join("valve.lock")) 
+/// What a reload (SIGHUP or the `Reload` command) changed.
+#[derive(Debug, Default, Serialize)]
+pub struct ReloadSummary {
+    /// Codebase ids added to, removed from or edited in `registry.json`
+    pub registry_added: Vec<String>,
+    pub registry_removed: Vec<String>,
+    pub registry_changed: Vec<String>,
+    /// Daemon settings that changed, by name
+    pub settings_changed: Vec<&'static str>,
+    /// Changed settings that need a daemon restart to apply
+    pub restart_required: Vec<&'static str>,
+    #[serde(flatten)]
+    pub watchers: Reconciled,
 }
 
-pub async fn run_foreground(port: u16) -> Result<()> {
+/// Run one valve instance rooted at `paths` until SIGINT/SIGTERM. Port 0
+/// binds an ephemeral port; the bound address is published for clients.
+pub async fn run_foreground(paths: &Paths, reloader: Reloader) -> Result<()> {
+    let mut settings = (reloader.settings)()?;
     // Single-instance lock
-    let lock_path = lockfile_path()?;
+    let lock_path = paths.lockfile();
     std::fs::create_dir_all(lock_path.parent().unwrap())?;
     let file = File::create(&lock_path)?;
     let mut lock = RwLock::new(file);
     let _lock_guard = lock.try_write().context("valve already running?")?;
 
     // Load or init registry
-    let reg = Arc::new(Registry::load_or_default()?);
+    let shared = SharedRegistry::new(Registry::load_or_default(&paths.registry())?);
+    let changed = Arc::new(Notify::new());
+    let board = StatusBoard::default();
 
     // Event sink: Chronicle NDJSON file
-    let chron_path = dirs()?.data_dir().join("chronicles");
-    std::fs::create_dir_all(&chron_path)?;
-    let chron_file = chron_path.join("valve.ndjson");
+    let sinks = Sinks::new(settings.chronicle.clone(), settings.min_severity, sink::bus(), HaltStore::at(paths.halts()), RunLog::at(paths.schedules()));
 
     // Start control-plane server
-    let reg_cp = reg.clone();
-    let chron_cp = chron_file.clone();
-    let ctrl = tokio::spawn(async move {
-        if let Err(e) = control::server(port, reg_cp, chron_cp).await { 
+    let listener = TcpListener::bind(SocketAddr::from(([127,0,0,1], settings.port))).await?;
+    let addr = listener.local_addr()?;
+    std::fs::write(paths.control_addr(), addr.to_string())?;
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let (reload_tx, mut reload_rx) = mpsc::unbounded_channel();
+    let ctx = control::Ctx { shared: shared.clone(), bus: sinks.bus.clone(), board: board.clone(), changed: changed.clone(), reload: reload_tx, stop: stop_rx };
+    let mut ctrl = tokio::spawn(async move {
+        if let Err(e) = control::server(listener, ctx).await { 
             error!(?e, "control plane exit"); 
         }
     });
 
+    // Rescan discovery roots in the background
+    let discovery = tokio::spawn(rescan_roots(shared.clone(), changed.clone(), Duration::from_secs(settings.discovery_interval_secs)));
+
     // Start supervisor over all codebases in registry
-    let mut sup = Supervisor::new(chron_file);
-    sup.reconcile(reg.clone()).await?; // spawn watchers for existing codebases
+    let mut sup = Supervisor::new(sinks, board, &settings);
+    let snapshot = || Arc::new(shared.0.read().clone());
+    sup.reconcile(snapshot(), false).await?; // spawn watchers for existing codebases
 
-    info!("valve running on port {}", port);
+    info!(%addr, data = %paths.data.display(), "valve running");
 
     // Handle reload signals (SIGHUP => reload registry)
     let mut hup = signal::unix::signal(signal::unix::SignalKind::hangup()).ok();
     let mut term = signal::unix::signal(signal::unix::SignalKind::terminate()).ok();
 
-    loop {
+    let reason = loop {
         tokio::select! {
-            _ = tokio::signal::ctrl_c() => { break; }
+            _ = tokio::signal::ctrl_c() => { break "SIGINT"; }
+            Some(id) = sup.expired() => {
+                match shared.0.write().remove_by_id_or_path(&id) {
+                    Ok(cb) => info!(%id, removed = cb.is_some(), "unregistered missing codebase"),
+                    Err(e) => error!(?e, "unregister missing codebase"),
+                }
+                changed.notify_one();
+            }
+            _ = changed.notified() => {
+                if let Err(e) = sup.reconcile(snapshot(), false).await { 
+                    error!(?e, "reconcile"); 
+                }
+            }
+            Some(reply) = reload_rx.recv() => {
+                let res = reload(paths, &shared, &mut sup, &reloader, &mut settings).await;
+                if let Err(e) = &res { error!(?e, "reload"); }
+                let _ = reply.send(res.map_err(|e| format!("{:#}", e)));
+            }
             _ = async { 
                 if let Some(s) = &mut hup { 
                     s.recv().await 
@@ -59,8 +107,8 @@ pub async fn run_foreground(port: u16) -> Result<()> {
                     std::future::pending().await 
                 } 
             } => {
-                if let Err(e) = sup.reconcile(reg.clone()).await { 
-                    error!(?e, "reconcile"); 
+                if let Err(e) = reload(paths, &shared, &mut sup, &reloader, &mut settings).await { 
+                    error!(?e, "reload"); 
                 }
             }
             _ = async { 
@@ -70,14 +118,140 @@ pub async fn run_foreground(port: u16) -> Result<()> {
                     std::future::pending().await 
                 } 
             } => { 
-                break; 
+                break "SIGTERM"; 
             }
         }
-    }
+    };
 
-    // graceful shutdown
-    sup.shutdown().await;
-    ctrl.abort();
-    info!("valve stopped");
+    // graceful shutdown: stop intake, drain, then sync sinks
+    info!(reason, "valve stopping");
+    let deadline = Instant::now() + Duration::from_secs(settings.shutdown_grace_secs);
+    let _ = stop_tx.send(true);
+    discovery.abort();
+    let watchers_clean = sup.shutdown(deadline).await;
+    let control_clean = timeout_at(deadline, &mut ctrl).await.is_ok();
+    if !control_clean { ctrl.abort(); }
+    let _ = std::fs::remove_file(paths.control_addr());
+    let stopped = json!({
+        "type": "VALVE_STOPPED",
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "reason": reason,
+        "clean": watchers_clean && control_clean,
+    });
+    let mut chron = NdjsonSink::open(&settings.chronicle, settings.min_severity)?;
+    chron.record(&stopped).await?;
+    chron.sync().await?;
+    info!(clean = watchers_clean && control_clean, "valve stopped");
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Re-read the registry, daemon settings and (via reconcile) every persona
+/// config, restarting only the watchers they affect.
+async fn reload(paths: &Paths, shared: &SharedRegistry, sup: &mut Supervisor, reloader: &Reloader, current: &mut Settings) -> Result<ReloadSummary> {
+    let fresh = Registry::load_or_default(&paths.registry())?;
+    let settings = (reloader.settings)()?;
+    let mut summary = ReloadSummary::default();
+    {
+        let old = shared.0.read();
+        for (id, cb) in &fresh.codebases {
+            match old.codebases.get(id) {
+                None => summary.registry_added.push(id.clone()),
+                Some(prev) if prev != cb => summary.registry_changed.push(id.clone()),
+                Some(_) => {}
+            }
+        }
+        summary.registry_removed = old.codebases.keys().filter(|id| !fresh.codebases.contains_key(*id)).cloned().collect();
+    }
+    *shared.0.write() = fresh;
+
+    if settings.log != current.log { (reloader.set_log_filter)(&settings.log)?; }
+    for (key, value, _) in &settings.sources {
+        if current.sources.iter().any(|(k, v, _)| k == key && v != value) {
+            summary.settings_changed.push(key);
+            if RESTART_REQUIRED.contains(key) { summary.restart_required.push(key); }
+        }
+    }
+    sup.configure(&settings);
+    *current = settings;
+
+    let snapshot = Arc::new(shared.0.read().clone());
+    summary.watchers = sup.reconcile(snapshot, true).await?;
+    info!(?summary, "reloaded");
+    Ok(summary)
+}
+
+/// Rescan watched discovery roots every `every`. The walk runs off the
+/// runtime and outside the registry lock; only applying what it found takes
+/// the lock.
+async fn rescan_roots(shared: SharedRegistry, changed: Arc<Notify>, every: Duration) {
+    let mut tick = interval(every);
+    loop {
+        tick.tick().await;
+        let roots = shared.0.read().roots.clone();
+        for root in roots {
+            let path = root.path.clone();
+            let scanned = tokio::task::spawn_blocking({ let root = root.clone(); move || discover::scan(root) }).await.unwrap_or_else(|e| Err(e.into()));
+            let applied = scanned.and_then(|(scanned, found)| {
+                let mut reg = shared.0.write();
+                // unregistered while we were walking
+                if !reg.roots.contains(&root) { return Ok(Discovered::default()); }
+                reg.apply_discovery(scanned, &found, true)
+            });
+            match applied {
+                Ok(d) if d.registered.is_empty() && d.unregistered.is_empty() => {}
+                Ok(d) => {
+                    info!(root = %path.display(), registered = d.registered.len(), unregistered = d.unregistered.len(), "discovery root changed");
+                    changed.notify_one();
+                }
+                Err(e) => warn!(?e, root = %path.display(), "discovery rescan failed"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{settings::Overrides, state::OverlapPolicy};
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_reload_reports_registry_and_settings_changes() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let paths = Paths::at(temp_dir.path().join("state"));
+        std::fs::create_dir_all(&paths.config).unwrap();
+        // no user layer, so nothing outside the temp dir is read
+        std::fs::write(paths.settings(), "user_config = \"\"\n").unwrap();
+        let [a, b, c, d] = ["a", "b", "c", "d"].map(|n| temp_dir.path().join(n));
+        for dir in [&a, &b, &c, &d] { std::fs::create_dir_all(dir).unwrap(); }
+        let mut reg = Registry::load_or_default(&paths.registry()).expect("Failed to load registry");
+        let [a, b, d] = [&a, &b, &d].map(|p| reg.add(p, OverlapPolicy::Reject).expect("Failed to add codebase").0.id);
+
+        let settings_paths = paths.clone();
+        let reloader = Reloader {
+            settings: Box::new(move || Settings::load_with(&settings_paths, true, &Overrides::default(), &|_| None)),
+            set_log_filter: Box::new(|_| Ok(())),
+        };
+        let mut settings = (reloader.settings)().expect("valid settings");
+        let sinks = Sinks::new(settings.chronicle.clone(), settings.min_severity, sink::bus(), HaltStore::at(paths.halts()), RunLog::at(paths.schedules()));
+        let shared = SharedRegistry::new(reg.clone());
+        let mut sup = Supervisor::new(sinks, StatusBoard::default(), &settings);
+        sup.reconcile(Arc::new(reg.clone()), false).await.unwrap();
+
+        // edit the registry and settings behind the daemon's back
+        reg.edit(&a, crate::state::CodebasePatch { alias: Some("api".into()), ..Default::default() }).unwrap();
+        reg.remove_by_id_or_path(&b).unwrap();
+        let c = reg.add(&c, OverlapPolicy::Reject).unwrap().0.id;
+        std::fs::write(paths.settings(), "user_config = \"\"\nforget_missing_after_secs = 60\ndiscovery_interval_secs = 5\n").unwrap();
+
+        let summary = reload(&paths, &shared, &mut sup, &reloader, &mut settings).await.expect("reload");
+        assert_eq!((summary.registry_added, summary.registry_removed, summary.registry_changed), (vec![c.clone()], vec![b.clone()], vec![a.clone()]));
+        assert_eq!(summary.settings_changed, vec!["forget_missing_after_secs", "discovery_interval_secs"]);
+        assert_eq!(summary.restart_required, vec!["discovery_interval_secs"]);
+        assert_eq!((summary.watchers.started, summary.watchers.stopped), (vec![c], vec![b]));
+        assert_eq!(summary.watchers.restarted, BTreeMap::from([(a, "registry"), (d, "settings")]));
+        assert_eq!(settings.forget_missing_after_secs, Some(60));
+        sup.shutdown(Instant::now() + Duration::from_secs(5)).await;
+    }
+}
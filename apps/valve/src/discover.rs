@@ -0,0 +1,79 @@
+use crate::state::OverlapPolicy;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::{Path, PathBuf}};
+
+/// A directory whose git checkouts are registered (and unregistered) automatically.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DiscoveryRoot {
+    pub path: PathBuf,
+    /// How many directory levels below `path` to search
+    pub depth: usize,
+    /// Only pick up repos that carry `.sage/valve.yml`
+    #[serde(default)]
+    pub require_config: bool,
+    /// Applied to each repo found, on every rescan
+    #[serde(default)]
+    pub on_overlap: OverlapPolicy,
+}
+
+/// Canonicalize `root` and find the repos under it, for
+/// [`crate::state::Registry::apply_discovery`]. This walks the tree, so keep
+/// it off the runtime and outside the registry lock.
+pub fn scan(root: DiscoveryRoot) -> Result<(DiscoveryRoot, Vec<PathBuf>)> {
+    let root = DiscoveryRoot { path: root.path.canonicalize().context("invalid discovery root")?, ..root };
+    let found = find_repos(&root.path, root.depth, root.require_config)?;
+    Ok((root, found))
+}
+
+/// Git repositories at most `depth` levels below `root`, canonicalized and
+/// sorted. Repos are not searched for nested repos, and hidden directories
+/// are skipped.
+pub fn find_repos(root: &Path, depth: usize, require_config: bool) -> Result<Vec<PathBuf>> {
+    let root = root.canonicalize().with_context(|| format!("invalid discovery root {}", root.display()))?;
+    let mut found = vec![];
+    walk(&root, depth, require_config, &mut found);
+    found.sort();
+    Ok(found)
+}
+
+fn walk(dir: &Path, depth: usize, require_config: bool, found: &mut Vec<PathBuf>) {
+    // `.git` is a file in worktrees and submodules
+    if dir.join(".git").exists() {
+        if !require_config || dir.join(".sage/valve.yml").is_file() { found.push(dir.to_path_buf()); }
+        return;
+    }
+    if depth == 0 { return; }
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.flatten() {
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        // file_type() does not follow symlinks, so links cannot loop the walk
+        if !hidden && entry.file_type().is_ok_and(|t| t.is_dir()) {
+            walk(&entry.path(), depth - 1, require_config, found);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_find_repos_respects_depth_and_config() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let root = temp_dir.path();
+        for repo in ["api", "team/web", "team/deep/lib", "api/vendor/dep"] {
+            fs::create_dir_all(root.join(repo).join(".git")).expect("Failed to create repo");
+        }
+        fs::create_dir_all(root.join(".cache/hidden/.git")).unwrap();
+        fs::create_dir_all(root.join("team/web/.sage")).unwrap();
+        fs::write(root.join("team/web/.sage/valve.yml"), "personas: {}\n").unwrap();
+
+        let canon = root.canonicalize().unwrap();
+        let names = |v: Vec<PathBuf>| v.iter().map(|p| p.strip_prefix(&canon).unwrap().to_string_lossy().to_string()).collect::<Vec<_>>();
+        assert_eq!(names(find_repos(root, 2, false).unwrap()), vec!["api", "team/web"]);
+        assert_eq!(names(find_repos(root, 3, false).unwrap()), vec!["api", "team/deep/lib", "team/web"]);
+        assert_eq!(names(find_repos(root, 3, true).unwrap()), vec!["team/web"]);
+    }
+}
@@ -0,0 +1,88 @@
+use crate::persona::Change;
+use anyhow::{bail, Context, Result};
+use std::{path::{Path, PathBuf}, process::Command};
+
+/// Run `git` in `repo` and return stdout, failing with git's stderr on a
+/// non-zero exit.
+pub fn git(repo: &Path, args: &[&str]) -> Result<Vec<u8>> {
+    let out = Command::new("git").arg("-C").arg(repo).args(args).output().context("spawn git")?;
+    if !out.status.success() {
+        bail!("git {} failed: {}", args.join(" "), String::from_utf8_lossy(&out.stderr).trim());
+    }
+    Ok(out.stdout)
+}
+
+pub fn git_str(repo: &Path, args: &[&str]) -> Result<String> {
+    Ok(String::from_utf8_lossy(&git(repo, args)?).trim().to_string())
+}
+
+pub fn toplevel(dir: &Path) -> Result<PathBuf> {
+    Ok(PathBuf::from(git_str(dir, &["rev-parse", "--show-toplevel"])?))
+}
+
+/// Split `-z` output into paths.
+pub fn split_z(raw: &[u8]) -> Vec<String> {
+    raw.split(|b| *b == 0).filter(|s| !s.is_empty()).map(|s| String::from_utf8_lossy(s).into_owned()).collect()
+}
+
+/// A path from `--raw -z` output, with the old path of a rename.
+pub struct Changed {
+    pub change: Change,
+    pub path: String,
+    pub from: Option<String>,
+    /// A submodule commit rather than a blob (mode 160000 on the side that
+    /// has content: the old one for deletes, the new one otherwise)
+    pub gitlink: bool,
+}
+
+/// Parse `--raw -z` output (run with `-M` to pair renames). Copies count as
+/// creates and type changes as modifies.
+pub fn raw_changes(raw: &[u8]) -> Vec<Changed> {
+    let mut fields = split_z(raw).into_iter();
+    let mut out = vec![];
+    while let (Some(header), Some(path)) = (fields.next(), fields.next()) {
+        // :<old mode> <new mode> <old sha> <new sha> <status>
+        let header: Vec<&str> = header.trim_start_matches(':').split(' ').collect();
+        let [old_mode, new_mode, _, _, status] = header[..] else { break };
+        let change = match status.as_bytes()[0] {
+            b'A' | b'C' => Change::Create,
+            b'D' => Change::Delete,
+            b'R' => Change::Rename,
+            _ => Change::Modify,
+        };
+        let gitlink = (if change == Change::Delete { old_mode } else { new_mode }) == "160000";
+        // renames and copies list the old path first
+        if matches!(status.as_bytes()[0], b'R' | b'C') {
+            let Some(to) = fields.next() else { break };
+            out.push(Changed { change, from: (change == Change::Rename).then_some(path), path: to, gitlink });
+        } else {
+            out.push(Changed { change, path, from: None, gitlink });
+        }
+    }
+    out
+}
+
+/// Decode a blob for trigger matching; binary content yields `None`.
+pub fn text(blob: Vec<u8>) -> Option<String> {
+    if blob.contains(&0) { return None; }
+    String::from_utf8(blob).ok()
+}
+
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    /// Initialise a throwaway repo with a committer identity.
+    pub fn init(dir: &Path) {
+        git(dir, &["init", "-q"]).unwrap();
+        git(dir, &["config", "user.email", "valve@example.com"]).unwrap();
+        git(dir, &["config", "user.name", "valve"]).unwrap();
+        git(dir, &["config", "commit.gpgsign", "false"]).unwrap();
+    }
+
+    pub fn commit_all(dir: &Path, msg: &str) -> String {
+        git(dir, &["add", "-A"]).unwrap();
+        git(dir, &["commit", "-q", "-m", msg]).unwrap();
+        git_str(dir, &["rev-parse", "HEAD"]).unwrap()
+    }
+}
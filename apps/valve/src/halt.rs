@@ -0,0 +1,164 @@
+use crate::{persona::ValveEvent, severity::Severity, sink::NdjsonSink, state};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+
+/// The HALT_EVERYTHING hit that put a codebase into the halted state.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Halt {
+    pub repo: String,
+    pub persona: String,
+    pub file: String,
+    pub reason: String,
+    pub since: i64, // unix millis
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HaltFile { halts: BTreeMap<String, Halt> } // key: canonical repo path
+
+/// Persisted halt state shared by the daemon (which sets it) and the
+/// `gate`/`ack` commands (which read and clear it).
+#[derive(Debug, Clone)]
+pub struct HaltStore { path: PathBuf }
+
+impl HaltStore {
+    pub fn at(path: PathBuf) -> Self { Self { path } }
+
+    pub fn get(&self, repo: &Path) -> Result<Option<Halt>> {
+        Ok(self.read()?.halts.remove(&key(repo)))
+    }
+
+    /// Record a halt for `ev.repo` unless one is already in place. Returns the
+    /// new halt, or `None` if the codebase was already halted.
+    pub fn trip(&self, ev: &ValveEvent) -> Result<Option<Halt>> {
+        self.update(|f| {
+            let k = key(Path::new(&ev.repo));
+            if f.halts.contains_key(&k) { return None; }
+            let h = Halt { repo: ev.repo.clone(), persona: ev.persona.clone(), file: ev.file.clone(), reason: ev.reason.clone(), since: ev.timestamp };
+            f.halts.insert(k, h.clone());
+            Some(h)
+        })
+    }
+
+    /// Clear the halt for `repo`, returning what was cleared.
+    pub fn clear(&self, repo: &Path) -> Result<Option<Halt>> {
+        self.update(|f| f.halts.remove(&key(repo)))
+    }
+
+    fn read(&self) -> Result<HaltFile> {
+        if !self.path.exists() { return Ok(HaltFile::default()); }
+        serde_json::from_str(&fs::read_to_string(&self.path)?).with_context(|| format!("corrupt halt state at {}", self.path.display()))
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut HaltFile) -> T) -> Result<T> {
+        state::with_lock(&self.path, || {
+            let mut file = self.read()?;
+            let out = f(&mut file);
+            state::write_atomic(&self.path, &serde_json::to_vec_pretty(&file)?)?;
+            Ok(out)
+        })
+    }
+}
+
+/// `sage-valve gate`: succeed only while the codebase is not halted.
+pub fn gate(store: &HaltStore, repo: &Path) -> Result<()> {
+    match store.get(repo)? {
+        Some(h) => {
+            eprintln!("HALTED by {} on {} ({}) since {}", h.persona, h.file, h.reason, chrono::DateTime::from_timestamp_millis(h.since).map(|t| t.to_rfc3339()).unwrap_or_default());
+            eprintln!("clear with: sage-valve ack {} --reason <why>", repo.display());
+            bail!("codebase halted: {}", h.repo)
+        }
+        None => { println!("open: {}", repo.display()); Ok(()) }
+    }
+}
+
+/// `sage-valve ack`: clear a halt and chronicle who cleared it and why.
+pub async fn ack(store: &HaltStore, chronicle: &Path, repo: &Path, by: &str, why: &str) -> Result<()> {
+    let Some(h) = store.clear(repo)? else { bail!("not halted: {}", repo.display()) };
+    let mut chron = NdjsonSink::open(chronicle, Severity::Info)?;
+    chron.record(&halt_acknowledged(&h, by, why)).await?;
+    chron.flush().await?;
+    println!("cleared halt on {} (persona {})", h.repo, h.persona);
+    Ok(())
+}
+
+fn key(repo: &Path) -> String {
+    repo.canonicalize().unwrap_or_else(|_| repo.to_path_buf()).to_string_lossy().to_string()
+}
+
+/// Chronicle record written when a HALT_EVERYTHING persona fires.
+pub fn halt_and_report(h: &Halt, response: Option<&str>) -> Value {
+    json!({
+        "type": "HALT_AND_REPORT",
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "actor": { "agent": "valve", "id": h.persona },
+        "invariant": format!("persona {} (HALT_EVERYTHING) must not fire", h.persona),
+        "expected": "no HALT_EVERYTHING hits",
+        "actual": { "file": h.file, "reason": h.reason },
+        "context": { "repo": h.repo, "response": response },
+    })
+}
+
+/// Chronicle record written when a human clears a halt.
+pub fn halt_acknowledged(h: &Halt, by: &str, why: &str) -> Value {
+    json!({
+        "type": "HALT_ACKNOWLEDGED",
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "actor": { "agent": "human", "id": by },
+        "reason": why,
+        "halt": h,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn event(repo: &Path) -> ValveEvent {
+        ValveEvent { persona: "Guardian".into(), repo: repo.display().to_string(), file: ".env".into(), reason: "glob".into(), severity: Severity::HaltEverything, timestamp: 42, ..Default::default() }
+    }
+
+    #[test]
+    fn test_trip_persists_until_cleared() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let store = HaltStore::at(temp_dir.path().join("state/halts.json"));
+        let repo = temp_dir.path();
+
+        assert!(store.get(repo).unwrap().is_none());
+        let h = store.trip(&event(repo)).unwrap().expect("first hit halts");
+        assert_eq!(h.persona, "Guardian");
+        // a second hit keeps the original halt
+        assert!(store.trip(&event(repo)).unwrap().is_none());
+
+        // state survives a fresh handle
+        let again = HaltStore::at(temp_dir.path().join("state/halts.json"));
+        assert_eq!(again.get(repo).unwrap(), Some(h.clone()));
+
+        assert_eq!(again.clear(repo).unwrap(), Some(h));
+        assert!(store.get(repo).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_gate_and_ack() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let store = HaltStore::at(temp_dir.path().join("halts.json"));
+        let chronicle = temp_dir.path().join("valve.ndjson");
+        let repo = temp_dir.path();
+
+        assert!(gate(&store, repo).is_ok());
+        store.trip(&event(repo)).unwrap();
+        assert!(gate(&store, repo).is_err());
+        assert!(ack(&store, &chronicle, repo, "alice", "rotated the key").await.is_ok());
+        assert!(gate(&store, repo).is_ok());
+
+        let raw = std::fs::read_to_string(&chronicle).unwrap();
+        let rec: Value = serde_json::from_str(raw.lines().next().unwrap()).unwrap();
+        assert_eq!(rec["type"], "HALT_ACKNOWLEDGED");
+        assert_eq!(rec["actor"]["id"], "alice");
+        assert_eq!(rec["reason"], "rotated the key");
+        // nothing left to acknowledge
+        assert!(ack(&store, &chronicle, repo, "alice", "again").await.is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use crate::{config, git, persona::{self, Change, Facts, ValveEvent}, severity::Severity};
+use anyhow::{bail, Context, Result};
+use std::{fs, path::{Path, PathBuf}};
+use tracing::warn;
+
+const MARKER: &str = "# installed by sage-valve";
+/// Name an existing, foreign pre-commit hook is moved to so ours can chain it.
+const CHAINED: &str = "pre-commit.pre-sage";
+
+/// Write `<hooks>/pre-commit`, moving any foreign hook aside and running it
+/// before the valve check.
+pub fn install(repo: &Path, min_severity: Severity) -> Result<PathBuf> {
+    let top = git::toplevel(repo)?;
+    let hooks = top.join(git::git_str(&top, &["rev-parse", "--git-path", "hooks"])?);
+    fs::create_dir_all(&hooks)?;
+    let hook = hooks.join("pre-commit");
+    if hook.exists() {
+        let existing = fs::read_to_string(&hook).unwrap_or_default();
+        if !existing.contains(MARKER) {
+            let chained = hooks.join(CHAINED);
+            if chained.exists() { bail!("{} already exists; refusing to overwrite it", chained.display()); }
+            fs::rename(&hook, &chained)?;
+        }
+    }
+    let exe = sh_quote(&std::env::current_exe().map(|p| p.display().to_string()).unwrap_or_else(|_| "sage-valve".into()));
+    let script = format!(r#"#!/bin/sh
+{MARKER}
+hook_dir=$(dirname "$0")
+if [ -x "$hook_dir/{CHAINED}" ]; then
+    "$hook_dir/{CHAINED}" "$@" || exit $?
+fi
+exec {exe} hook run --min-severity "${{SAGE_VALVE_HOOK_THRESHOLD:-{min_severity}}}"
+"#);
+    fs::write(&hook, script)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(hook)
+}
+
+/// Quote `s` as one word for `sh`.
+fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Evaluate personas against the staged (index) contents of every staged
+/// path; deleted paths are evaluated as of `HEAD`, by personas that ask for
+/// deletes. Submodules are skipped, and so are paths git cannot show. With
+/// no `.sage/valve.yml` (removed or renamed since the hook was installed)
+/// there is nothing to enforce. `user` is the user config layer, if any.
+pub fn staged_hits(repo: &Path, user: Option<&Path>) -> Result<Vec<ValveEvent>> {
+    let top = git::toplevel(repo)?;
+    let config = top.join(".sage/valve.yml");
+    if !config.is_file() {
+        eprintln!("sage-valve: no {}; skipping the pre-commit check", config.display());
+        return Ok(vec![]);
+    }
+    let personas = config::load_layered(&top, &config, user).context("pre-commit needs .sage/valve.yml")?;
+    let changed = git::raw_changes(&git::git(&top, &["diff", "--cached", "--raw", "-z", "-M"])?);
+    let mut hits = vec![];
+    for c in changed.into_iter().filter(|c| !c.gitlink) {
+        let blob = match c.change {
+            Change::Delete => format!("HEAD:{}", c.path),
+            _ => format!(":{}", c.path),
+        };
+        let text = match git::git(&top, &["show", &blob]) {
+            Ok(raw) => git::text(raw),
+            Err(e) => { warn!(path = %c.path, ?e, "cannot read staged content; skipping"); continue; }
+        };
+        let facts = Facts::new(&top, Path::new(&c.path), text.as_deref()).with_change(c.change);
+        let facts = match &c.from { Some(from) => facts.renamed_from(Path::new(from)), None => facts };
+        hits.extend(persona::match_facts(&personas, &facts));
+    }
+    Ok(hits)
+}
+
+/// `sage-valve hook run`: print every hit and fail if any reaches `min_severity`.
+pub fn run(repo: &Path, min_severity: Severity, user: Option<&Path>) -> Result<()> {
+    let hits = staged_hits(repo, user)?;
+    for ev in &hits {
+        eprintln!("[{}] {} {} ({})", ev.severity, ev.persona, ev.file, ev.reason);
+    }
+    let blocking = hits.iter().filter(|e| e.severity >= min_severity).count();
+    if blocking > 0 {
+        bail!("sage-valve: {} hit(s) at or above {}; commit blocked", blocking, min_severity);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing;
+    use tempfile::TempDir;
+
+    fn repo_with_config() -> TempDir {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        testing::init(temp_dir.path());
+        let sage_dir = temp_dir.path().join(".sage");
+        std::fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::write(sage_dir.join("valve.yml"), r#"
+personas:
+  TypeNazi:
+    filters: ["**/*.ts"]
+    triggers: ["as any"]
+    severity: "high"
+"#).expect("Failed to write config file");
+        testing::commit_all(temp_dir.path(), "init");
+        temp_dir
+    }
+
+    #[test]
+    fn test_evaluates_index_not_worktree() {
+        let temp_dir = repo_with_config();
+        let file = temp_dir.path().join("a.ts");
+        fs::write(&file, "const x = y as any;\n").unwrap();
+        git::git(temp_dir.path(), &["add", "a.ts"]).unwrap();
+        // the working tree is clean, the index is not
+        fs::write(&file, "const x = y;\n").unwrap();
+
+        let hits = staged_hits(temp_dir.path(), None).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].persona, "TypeNazi");
+        assert!(run(temp_dir.path(), Severity::High, None).is_err());
+        assert!(run(temp_dir.path(), Severity::Critical, None).is_ok());
+    }
+
+    #[test]
+    fn test_staged_renames_and_deletes() {
+        let temp_dir = repo_with_config();
+        fs::write(temp_dir.path().join(".sage/valve.yml"), r#"
+personas:
+  TypeNazi:
+    filters: ["**/*.ts"]
+    triggers: ["as any"]
+    on: [delete, rename]
+"#).unwrap();
+        fs::write(temp_dir.path().join("a.ts"), "const x = y as any;\n").unwrap();
+        fs::write(temp_dir.path().join("b.ts"), "const x = y as any;\n").unwrap();
+        fs::write(temp_dir.path().join("c.ts"), "const x = y;\n").unwrap();
+        testing::commit_all(temp_dir.path(), "ts");
+        git::git(temp_dir.path(), &["mv", "a.ts", "moved.ts"]).unwrap();
+        git::git(temp_dir.path(), &["rm", "-q", "b.ts", "c.ts"]).unwrap();
+
+        // c.ts was deleted too, but its last content has no trigger
+        let mut hits: Vec<_> = staged_hits(temp_dir.path(), None).unwrap().into_iter().map(|e| (e.kind, e.file, e.from)).collect();
+        hits.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(hits, vec![
+            (Some(Change::Delete), "b.ts".to_string(), None),
+            (Some(Change::Rename), "moved.ts".to_string(), Some("a.ts".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn test_deletes_submodules_and_missing_config_do_not_block() {
+        let temp_dir = repo_with_config();
+        let repo = temp_dir.path();
+        fs::write(repo.join("a.ts"), "const x = y as any;\n").unwrap();
+        let head = testing::commit_all(repo, "ts");
+
+        // removing offending code is not punished by a persona that never asked about deletes
+        git::git(repo, &["rm", "-q", "a.ts"]).unwrap();
+        // a submodule has no blob to show
+        git::git(repo, &["update-index", "--add", "--cacheinfo", &format!("160000,{head},sub")]).unwrap();
+        assert!(staged_hits(repo, None).unwrap().is_empty());
+        assert!(run(repo, Severity::High, None).is_ok());
+
+        git::git(repo, &["rm", "-q", "--cached", "sub"]).unwrap();
+        testing::commit_all(repo, "rm");
+        fs::write(repo.join("b.ts"), "const x = y as any;\n").unwrap();
+        git::git(repo, &["add", "b.ts"]).unwrap();
+        git::git(repo, &["mv", ".sage/valve.yml", ".sage/valve.yml.off"]).unwrap();
+        assert!(run(repo, Severity::High, None).is_ok());
+    }
+
+    #[test]
+    fn test_install_chains_existing_hook() {
+        let temp_dir = repo_with_config();
+        let hooks = temp_dir.path().join(".git/hooks");
+        fs::create_dir_all(&hooks).unwrap();
+        fs::write(hooks.join("pre-commit"), "#!/bin/sh\necho theirs\n").unwrap();
+
+        let hook = install(temp_dir.path(), Severity::High).unwrap();
+        let script = fs::read_to_string(&hook).unwrap();
+        assert!(script.contains(MARKER));
+        assert!(script.contains(CHAINED));
+        assert!(script.contains("exec '"), "{script}");
+        assert_eq!(sh_quote("/opt/it's $HOME/`x`"), r"'/opt/it'\''s $HOME/`x`'");
+        assert_eq!(fs::read_to_string(hooks.join(CHAINED)).unwrap(), "#!/bin/sh\necho theirs\n");
+
+        // reinstalling replaces our hook without re-chaining it
+        install(temp_dir.path(), Severity::Critical).unwrap();
+        assert_eq!(fs::read_to_string(hooks.join(CHAINED)).unwrap(), "#!/bin/sh\necho theirs\n");
+    }
+}
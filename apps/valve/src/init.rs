@@ -0,0 +1,121 @@
+use crate::{catalog, config};
+use anyhow::{bail, Result};
+use std::{collections::BTreeSet, fmt::Write, fs, path::{Path, PathBuf}};
+
+/// How deep `init` looks for stack markers (monorepo packages included).
+const SCAN_DEPTH: usize = 3;
+
+/// A builtin persona `init` proposes, and why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub name: &'static str,
+    pub builtin: &'static str,
+    /// What in the repo suggested it; `None` means offered but left commented out
+    pub because: Option<String>,
+}
+
+/// Look at the repo's files and pick builtin personas that fit its stack.
+pub fn detect(repo: &Path) -> Vec<Suggestion> {
+    let files: BTreeSet<String> = ignore::WalkBuilder::new(repo).hidden(false).max_depth(Some(SCAN_DEPTH))
+        .filter_entry(|e| !matches!(e.file_name().to_str(), Some(".git" | "node_modules" | "target")))
+        .build().flatten()
+        .filter_map(|e| e.path().strip_prefix(repo).ok().map(|p| p.to_string_lossy().replace('\\', "/")))
+        .collect();
+    let named = |f: &str| files.iter().find(|p| p.rsplit('/').next() == Some(f)).cloned();
+    let any = |pred: &dyn Fn(&str) -> bool| files.iter().find(|p| pred(p.rsplit('/').next().unwrap_or(p))).cloned();
+    let package_json = files.iter().filter(|p| p.rsplit('/').next() == Some("package.json"))
+        .filter_map(|p| fs::read_to_string(repo.join(p)).ok()).collect::<Vec<_>>().join("\n");
+
+    let mut found = vec![];
+    let mut add = |name, builtin, because: Option<String>| found.push(Suggestion { name, builtin, because });
+    add("RustWarden", "rustwarden", named("Cargo.toml"));
+    add("TypeNazi", "typenazi", named("tsconfig.json"));
+    add("ReactWarden", "reactwarden", package_json.contains("\"react\"").then(|| "react in package.json".to_string()));
+    let tests = ["\"vitest\"", "\"jest\""].iter().find(|t| package_json.contains(*t)).map(|t| format!("{} in package.json", t.trim_matches('"')))
+        .or_else(|| any(&|f| f.contains(".test.ts")));
+    add("TestMaster", "testmaster", tests);
+    add("DockerWatch", "dockerwatch", any(&|f| f == "Dockerfile" || f.starts_with("Dockerfile.") || f.starts_with("docker-compose")));
+    add("ConfigVulture", "configvulture", any(&|f| f.starts_with(".env")));
+    add("Sage", "sage", named("CLAUDE.md").or_else(|| named("CONTRACT.md")).or_else(|| files.iter().find(|p| p.contains("architecture/")).cloned()));
+    // halting on every touch is too much to switch on unasked
+    add("Guardian", "guardian", None);
+    found
+}
+
+/// A commented `valve.yml` enabling the detected personas and listing the
+/// rest as commented-out options.
+pub fn render(repo_name: &str, suggestions: &[Suggestion]) -> String {
+    let catalog = catalog::catalog(catalog::LATEST).unwrap_or_default();
+    let describe = |s: &Suggestion| catalog.get(s.builtin).map(|e| e.description.clone()).unwrap_or_default();
+    let mut out = String::new();
+    let _ = writeln!(out, "# Sage Valve personas for {}", repo_name);
+    let _ = writeln!(out, "# Generated by `sage-valve init`. `sage-valve personas catalog` lists the");
+    let _ = writeln!(out, "# builtins; override any field next to `use:`, and run `sage-valve config");
+    let _ = writeln!(out, "# explain` to see what each persona ends up watching.");
+    let _ = writeln!(out, "catalog: {}\n", catalog::LATEST);
+
+    let (on, off): (Vec<_>, Vec<_>) = suggestions.iter().partition(|s| s.because.is_some());
+    let _ = writeln!(out, "personas:{}", if on.is_empty() { " {}" } else { "" });
+    for s in &on {
+        let _ = writeln!(out, "  # {} (found {})", describe(s), s.because.as_deref().unwrap_or_default());
+        let _ = writeln!(out, "  {}:\n    use: builtin/{}\n", s.name, s.builtin);
+    }
+    if !off.is_empty() {
+        let _ = writeln!(out, "  # Also available; uncomment to enable:");
+        for s in &off {
+            let _ = writeln!(out, "  #\n  # {}\n  # {}:\n  #   use: builtin/{}", describe(s), s.name, s.builtin);
+        }
+    }
+    out
+}
+
+/// `sage-valve init`: write `.sage/valve.yml` for `repo`, refusing to
+/// replace an existing one unless `force`.
+pub fn init(repo: &Path, force: bool) -> Result<PathBuf> {
+    let file = repo.join(".sage/valve.yml");
+    if file.exists() && !force { bail!("{} already exists; pass --force to replace it", file.display()); }
+    let name = repo.canonicalize()?.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let yaml = render(&name, &detect(repo));
+    // never write something the daemon would reject
+    if let Err(d) = config::check(&yaml) { bail!("generated config is invalid: {}", d[0]); }
+    crate::state::write_atomic(&file, yaml.as_bytes())?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ValveConfig;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_init_scaffolds_for_the_stack() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let repo = temp_dir.path();
+        fs::write(repo.join("Cargo.toml"), "[package]\nname = \"x\"\n").unwrap();
+        fs::create_dir_all(repo.join("web")).unwrap();
+        fs::write(repo.join("web/package.json"), r#"{"dependencies":{"react":"18"},"devDependencies":{"vitest":"1"}}"#).unwrap();
+        fs::write(repo.join("web/tsconfig.json"), "{}").unwrap();
+        fs::write(repo.join(".env.example"), "KEY=\n").unwrap();
+
+        let file = init(repo, false).expect("init");
+        let config = ValveConfig::load_from_file(&file, None).expect("generated config loads");
+        let mut names: Vec<_> = config.personas.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, vec!["ConfigVulture", "ReactWarden", "RustWarden", "TestMaster", "TypeNazi"]);
+        assert!(fs::read_to_string(&file).unwrap().contains("# Guardian:"));
+
+        fs::write(&file, "personas: {}\n").unwrap();
+        assert!(init(repo, false).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "personas: {}\n");
+        init(repo, true).expect("forced init");
+        assert!(fs::read_to_string(&file).unwrap().contains("RustWarden"));
+    }
+
+    #[test]
+    fn test_empty_repo_gets_a_valid_config() {
+        let yaml = render("empty", &detect(TempDir::new().unwrap().path()));
+        assert!(yaml.contains("personas: {}"));
+        assert!(config::check(&yaml).is_ok());
+    }
+}
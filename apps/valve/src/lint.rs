@@ -0,0 +1,210 @@
+use crate::config::{self, Diagnostic, PersonaConfig, Spans, ValveConfig};
+use anyhow::{bail, Context, Result};
+use globset::Glob;
+use regex_syntax::hir::{Hir, HirKind};
+use serde::Serialize;
+use std::{fmt, io::Read, path::{Path, PathBuf}};
+
+/// Compiled programs bigger than this are flagged (the hard limit is 10 MiB).
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+/// Counted repetitions above this are unrolled into large programs.
+const MAX_REPETITION: u32 = 100;
+/// Extensions whose contents are never text.
+const BINARY_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "ico", "webp", "pdf", "zip", "gz", "tgz", "xz", "7z", "jar", "class",
+    "exe", "dll", "so", "dylib", "a", "o", "wasm", "woff", "woff2", "ttf", "otf", "mp3", "mp4", "mov", "bin",
+];
+
+/// A persona that is valid but probably not doing what was meant.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Lint {
+    /// Stable name for the kind of problem, e.g. `dead-glob`
+    pub code: &'static str,
+    #[serde(flatten)]
+    pub at: Diagnostic,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{}]", self.at, self.code)
+    }
+}
+
+/// Lint the personas in `file` (with its includes and `extends`
+/// resolved) against the files under `repo`.
+pub fn lint(file: &Path, repo: &Path, user: Option<&Path>) -> Result<Vec<Lint>> {
+    let cfg = ValveConfig::load_from_file(file, user).context("run `config check` first")?;
+    let raw = std::fs::read_to_string(file)?;
+    let spans = Spans::index(&raw).map_err(|d| anyhow::anyhow!("{}", d))?;
+    let files = repo_files(repo);
+
+    // in source order, so duplicates are reported on the later persona
+    let mut personas: Vec<(&String, &PersonaConfig)> = cfg.personas.iter().collect();
+    personas.sort_by_key(|(name, _)| spans.locate(&format!("personas.{name}")));
+
+    let mut lints = vec![];
+    let mut push = |code, name: &str, field: Option<String>, message: String| {
+        let path = field.as_ref().map_or(format!("personas.{name}"), |f| format!("personas.{name}.{f}"));
+        let (line, column) = spans.locate(&path);
+        lints.push(Lint { code, at: Diagnostic { line, column, persona: Some(name.to_string()), field, message } });
+    };
+    for (i, (name, p)) in personas.iter().enumerate() {
+        let filters = p.filters.clone().unwrap_or_default();
+        let triggers = p.triggers.as_ref().map(|t| t.patterns("triggers")).unwrap_or_default();
+
+        if filters.is_empty() && !triggers.is_empty() {
+            push("match-all", name, None, "no filters, so triggers run against every file in the repo".into());
+        }
+        let mut matched = vec![];
+        for (j, g) in filters.iter().enumerate() {
+            let m = Glob::new(g)?.compile_matcher();
+            let hits: Vec<&PathBuf> = files.iter().filter(|f| m.is_match(f)).collect();
+            if hits.is_empty() { push("dead-glob", name, Some(format!("filters[{j}]")), format!("`{}` matches no file in the repo", g)); }
+            matched.extend(hits);
+        }
+        // the watcher only runs triggers on text, and fires on the glob alone otherwise
+        let binary_globs = !filters.is_empty() && filters.iter().all(|g| is_binary_glob(g));
+        let binary_files = !matched.is_empty() && matched.iter().all(|f| is_binary_file(&repo.join(f)));
+        if !triggers.is_empty() && (binary_globs || binary_files) {
+            push("binary-triggers", name, Some("triggers".into()), "filters only match binary files, where triggers are not evaluated; the persona fires on every match".into());
+        }
+        if let Some((other, _)) = personas[..i].iter().find(|(_, q)| same(&q.filters, &p.filters) && same(&q.triggers, &p.triggers)) {
+            push("duplicate", name, None, format!("same filters and triggers as `{}`", other));
+        }
+        for (path, r) in triggers {
+            for (code, message) in regex_cost(r) { push(code, name, Some(path.clone()), message); }
+        }
+    }
+    lints.sort_by_key(|l| (l.at.line, l.at.column));
+    Ok(lints)
+}
+
+/// `config lint`: print lints for a repo's config; fail if `strict` and
+/// there were any.
+pub fn lint_repo(repo: &Path, json: bool, strict: bool, user: Option<&Path>) -> Result<()> {
+    let file = repo.join(".sage/valve.yml");
+    if ValveConfig::load_from_file(&file, user).is_err() { return config::check_files(&[file], json, user); }
+    let lints = lint(&file, repo, user)?;
+    for l in &lints {
+        if json {
+            let mut v = serde_json::to_value(l)?;
+            v["file"] = serde_json::json!(file);
+            println!("{}", v);
+        } else {
+            println!("{}:{}", file.display(), l);
+        }
+    }
+    if strict && !lints.is_empty() { bail!("{} warning{} found", lints.len(), if lints.len() == 1 { "" } else { "s" }); }
+    Ok(())
+}
+
+/// Repo-relative paths of every file in the repo, ignored or not: personas
+/// often guard files git never sees, like `.env`.
+fn repo_files(repo: &Path) -> Vec<PathBuf> {
+    ignore::WalkBuilder::new(repo).standard_filters(false)
+        .filter_entry(|e| e.file_name() != ".git")
+        .build().flatten()
+        .filter(|e| e.file_type().is_some_and(|t| t.is_file()))
+        .filter_map(|e| e.path().strip_prefix(repo).ok().map(Path::to_path_buf))
+        .collect()
+}
+
+fn is_binary_glob(glob: &str) -> bool {
+    glob.rsplit_once('.').is_some_and(|(_, ext)| BINARY_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Same heuristic as git: a NUL byte in the first 8 KiB.
+fn is_binary_file(path: &Path) -> bool {
+    let mut head = Vec::with_capacity(8192);
+    std::fs::File::open(path).and_then(|f| f.take(8192).read_to_end(&mut head)).is_ok() && head.contains(&0)
+}
+
+/// Equal, comparing lists as sets (a missing list counts as empty).
+fn same<T: Serialize>(a: &Option<T>, b: &Option<T>) -> bool {
+    let norm = |v: &Option<T>| match serde_json::to_value(v).unwrap_or_default() {
+        serde_json::Value::Null => vec![],
+        serde_json::Value::Array(items) => { let mut v: Vec<String> = items.iter().map(|i| i.to_string()).collect(); v.sort(); v.dedup(); v }
+        other => vec![other.to_string()],
+    };
+    norm(a) == norm(b)
+}
+
+/// Reasons a trigger is expensive to compile or to run.
+fn regex_cost(pattern: &str) -> Vec<(&'static str, String)> {
+    let mut found = vec![];
+    if regex::RegexBuilder::new(pattern).size_limit(REGEX_SIZE_LIMIT).build().is_err() {
+        found.push(("regex-size", format!("compiles to more than {} KiB", REGEX_SIZE_LIMIT >> 10)));
+    }
+    if let Ok(hir) = regex_syntax::parse(pattern) {
+        if let Some(n) = largest_repetition(&hir).filter(|&n| n > MAX_REPETITION) {
+            found.push(("slow-regex", format!("counted repetition of {} is unrolled; use a smaller bound or `+`", n)));
+        }
+    }
+    // matches are only tested for, so a leading `.*` just adds scanning
+    if pattern.starts_with(".*") {
+        found.push(("slow-regex", "leading `.*` is redundant and makes every match scan the line".into()));
+    }
+    found
+}
+
+fn largest_repetition(hir: &Hir) -> Option<u32> {
+    match hir.kind() {
+        HirKind::Repetition(r) => [Some(r.min), r.max, largest_repetition(&r.sub)].into_iter().flatten().max(),
+        HirKind::Capture(c) => largest_repetition(&c.sub),
+        HirKind::Concat(v) | HirKind::Alternation(v) => v.iter().filter_map(largest_repetition).max(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_lint_flags_dead_shadowed_and_expensive_personas() {
+        let config_str = r#"personas:
+  Rust:
+    filters: ["**/*.rs", "**/*.go"]
+    triggers: ["unsafe"]
+  RustAgain:
+    filters: ["**/*.rs", "**/*.go"]
+    triggers: ["unsafe"]
+  Images:
+    filters: ["**/*.png"]
+    triggers: ["secret"]
+  Everything:
+    triggers: ["TODO"]
+  Slow:
+    filters: ["**/*.rs"]
+    triggers: ["\\w{120}", ".*password"]
+  Secrets:
+    filters: ["**/.env*"]
+"#;
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let repo = temp_dir.path();
+        fs::create_dir_all(repo.join("src")).unwrap();
+        fs::write(repo.join("src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(repo.join("logo.png"), [0x89, b'P', b'N', b'G', 0, 0]).unwrap();
+        // ignored files still count
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::write(repo.join(".gitignore"), ".env*\n").unwrap();
+        fs::write(repo.join(".env.local"), "KEY=1\n").unwrap();
+        fs::create_dir_all(repo.join(".sage")).unwrap();
+        fs::write(repo.join(".sage/valve.yml"), config_str).unwrap();
+
+        let lints = lint(&repo.join(".sage/valve.yml"), repo, None).expect("lint");
+        let found: Vec<_> = lints.iter().map(|l| (l.code, l.at.persona.as_deref().unwrap(), l.at.field.as_deref())).collect();
+        assert_eq!(found, vec![
+            ("dead-glob", "Rust", Some("filters[1]")),
+            ("duplicate", "RustAgain", None),
+            ("dead-glob", "RustAgain", Some("filters[1]")),
+            ("binary-triggers", "Images", Some("triggers")),
+            ("match-all", "Everything", None),
+            ("regex-size", "Slow", Some("triggers[0]")),
+            ("slow-regex", "Slow", Some("triggers[0]")),
+            ("slow-regex", "Slow", Some("triggers[1]")),
+        ]);
+    }
+}
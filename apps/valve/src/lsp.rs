@@ -0,0 +1,339 @@
+use crate::{config::{self, CompiledPersona}, persona, severity::Severity};
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::{collections::HashMap, path::{Path, PathBuf}};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tracing::{debug, info, warn};
+
+const SOURCE: &str = "sage-valve";
+
+/// Marker recognised on the hit line (or the line above) to silence a persona.
+pub const SUPPRESS_MARKER: &str = "sage-valve: ignore";
+
+// LSP DiagnosticSeverity values
+const SEV_ERROR: u8 = 1;
+const SEV_WARNING: u8 = 2;
+const SEV_INFO: u8 = 3;
+const SEV_HINT: u8 = 4;
+
+struct Server {
+    root: PathBuf,
+    user: Option<PathBuf>,
+    personas: Vec<CompiledPersona>,
+    min_severity: Severity,
+    docs: HashMap<String, String>, // key: document uri
+}
+
+/// Run the language server over stdin/stdout until the client sends `exit`.
+pub async fn run_stdio(min_severity: Severity, user: Option<PathBuf>) -> Result<()> {
+    serve(BufReader::new(tokio::io::stdin()), tokio::io::stdout(), min_severity, user).await
+}
+
+pub async fn serve<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(mut r: R, mut w: W, min_severity: Severity, user: Option<PathBuf>) -> Result<()> {
+    let mut srv = Server { root: std::env::current_dir()?, user, personas: vec![], min_severity, docs: HashMap::new() };
+    while let Some(msg) = read_message(&mut r).await? {
+        let method = msg.get("method").and_then(Value::as_str).unwrap_or_default().to_string();
+        let id = msg.get("id").cloned();
+        let params = msg.get("params").cloned().unwrap_or(Value::Null);
+        debug!(%method, "lsp message");
+        match method.as_str() {
+            "initialize" => {
+                srv.root = workspace_root(&params).unwrap_or_else(|| srv.root.clone());
+                srv.reload();
+                let caps = json!({
+                    "textDocumentSync": { "openClose": true, "change": 1, "save": { "includeText": true } },
+                    "codeActionProvider": true,
+                });
+                reply(&mut w, id, json!({ "capabilities": caps, "serverInfo": { "name": SOURCE } })).await?;
+            }
+            "shutdown" => reply(&mut w, id, Value::Null).await?,
+            "exit" => break,
+            "textDocument/didOpen" => {
+                let doc = &params["textDocument"];
+                let uri = doc["uri"].as_str().unwrap_or_default().to_string();
+                srv.docs.insert(uri.clone(), doc["text"].as_str().unwrap_or_default().to_string());
+                srv.publish(&mut w, &uri).await?;
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+                // full sync: the last change carries the whole document
+                if let Some(text) = params["contentChanges"].as_array().and_then(|c| c.last()).and_then(|c| c["text"].as_str()) {
+                    srv.docs.insert(uri.clone(), text.to_string());
+                }
+                srv.publish(&mut w, &uri).await?;
+            }
+            "textDocument/didSave" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+                let path = uri_to_path(&uri);
+                if let Some(text) = params["text"].as_str() {
+                    srv.docs.insert(uri.clone(), text.to_string());
+                } else if let Ok(text) = std::fs::read_to_string(&path) {
+                    srv.docs.insert(uri.clone(), text);
+                }
+                if path.ends_with(".sage/valve.yml") {
+                    srv.reload();
+                    let uris: Vec<_> = srv.docs.keys().cloned().collect();
+                    for u in uris { srv.publish(&mut w, &u).await?; }
+                } else {
+                    srv.publish(&mut w, &uri).await?;
+                }
+            }
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_string();
+                srv.docs.remove(&uri);
+                notify(&mut w, "textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": [] })).await?;
+            }
+            "textDocument/codeAction" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let text = srv.docs.get(uri).map(String::as_str).unwrap_or_default();
+                let diags = params["context"]["diagnostics"].as_array().cloned().unwrap_or_default();
+                reply(&mut w, id, Value::Array(code_actions(uri, text, &diags))).await?;
+            }
+            _ => {
+                // unknown requests get an error; unknown notifications are ignored
+                if let Some(id) = id {
+                    let err = json!({ "jsonrpc": "2.0", "id": id, "error": { "code": -32601, "message": format!("method not found: {}", method) } });
+                    write_message(&mut w, &err).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Server {
+    fn reload(&mut self) {
+        self.personas = match config::load_layered(&self.root, &self.root.join(".sage/valve.yml"), self.user.as_deref()) {
+            Ok(p) => p.into_iter().filter(|p| p.severity >= self.min_severity).collect(),
+            Err(e) => { warn!(?e, root=%self.root.display(), "no usable valve.yml; serving no personas"); vec![] }
+        };
+        info!(root=%self.root.display(), personas = self.personas.len(), "lsp config loaded");
+    }
+
+    async fn publish<W: AsyncWrite + Unpin>(&self, w: &mut W, uri: &str) -> Result<()> {
+        let Some(text) = self.docs.get(uri) else { return Ok(()) };
+        let path = uri_to_path(uri);
+        let rel = path.strip_prefix(&self.root).unwrap_or(&path);
+        let diags = diagnostics(&self.personas, &self.root, rel, text);
+        notify(w, "textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": diags })).await
+    }
+}
+
+/// Build one diagnostic per trigger match (or one at the top of the file for
+/// glob-only personas), skipping locations carrying a suppression comment.
+pub fn diagnostics(personas: &[CompiledPersona], repo: &Path, rel: &Path, text: &str) -> Vec<Value> {
+    let mut out = vec![];
+    for ev in persona::match_personas(personas, repo, rel, Some(text)) {
+        let Some(p) = personas.iter().find(|p| p.name == ev.persona) else { continue };
+        let spans: Vec<(usize, usize)> = if p.triggers.is_empty() {
+            vec![(0, text.find('\n').unwrap_or(text.len()))]
+        } else {
+            p.triggers.iter().flat_map(|re| re.find_iter(text).map(|m| (m.start(), m.end()))).collect()
+        };
+        for (start, end) in spans {
+            let (start, end) = (position_at(text, start), position_at(text, end));
+            if is_suppressed(text, start.0, &p.name) { continue; }
+            out.push(json!({
+                "range": { "start": { "line": start.0, "character": start.1 }, "end": { "line": end.0, "character": end.1 } },
+                "severity": lsp_severity(p.severity),
+                "source": SOURCE,
+                "code": p.name,
+                "message": p.response.clone().unwrap_or_else(|| p.name.clone()),
+            }));
+        }
+    }
+    out
+}
+
+/// Map a persona severity onto an LSP DiagnosticSeverity.
+fn lsp_severity(s: Severity) -> u8 {
+    match s {
+        Severity::HaltEverything | Severity::Critical | Severity::High => SEV_ERROR,
+        Severity::Medium => SEV_WARNING,
+        Severity::Low => SEV_INFO,
+        Severity::Info => SEV_HINT,
+    }
+}
+
+/// True if `line` or the line above carries `sage-valve: ignore` naming the
+/// persona (or naming nothing, which silences every persona).
+pub fn is_suppressed(text: &str, line: usize, persona: &str) -> bool {
+    let lines: Vec<&str> = text.lines().collect();
+    let candidates = [Some(line), line.checked_sub(1)];
+    candidates.into_iter().flatten().filter_map(|i| lines.get(i)).any(|l| {
+        let Some(idx) = l.find(SUPPRESS_MARKER) else { return false };
+        let names: Vec<&str> = l[idx + SUPPRESS_MARKER.len()..].split(|c: char| c == ',' || c.is_whitespace()).filter(|s| !s.is_empty() && *s != "*/" && *s != "-->").collect();
+        names.is_empty() || names.contains(&persona)
+    })
+}
+
+/// Quick fixes that insert a suppression comment above each valve diagnostic.
+fn code_actions(uri: &str, text: &str, diags: &[Value]) -> Vec<Value> {
+    let lines: Vec<&str> = text.lines().collect();
+    let (open, close) = comment_syntax(&uri_to_path(uri));
+    diags.iter().filter(|d| d["source"] == SOURCE).filter_map(|d| {
+        let persona = d["code"].as_str()?;
+        let line = d["range"]["start"]["line"].as_u64()? as usize;
+        let indent: String = lines.get(line).map(|l| l.chars().take_while(|c| c.is_whitespace()).collect()).unwrap_or_default();
+        let comment = format!("{}{} {} {}{}\n", indent, open, SUPPRESS_MARKER, persona, close);
+        let edit = json!({ "range": { "start": { "line": line, "character": 0 }, "end": { "line": line, "character": 0 } }, "newText": comment });
+        Some(json!({
+            "title": format!("Suppress {} here", persona),
+            "kind": "quickfix",
+            "diagnostics": [d],
+            "edit": { "changes": { uri: [edit] } },
+        }))
+    }).collect()
+}
+
+fn comment_syntax(path: &Path) -> (&'static str, &'static str) {
+    match path.extension().and_then(|e| e.to_str()).unwrap_or_default() {
+        "py" | "rb" | "sh" | "bash" | "zsh" | "yml" | "yaml" | "toml" | "r" | "pl" | "ex" | "exs" | "conf" | "env" | "dockerfile" => ("#", ""),
+        "sql" | "lua" | "hs" => ("--", ""),
+        "html" | "xml" | "md" | "vue" | "svelte" => ("<!--", " -->"),
+        "css" | "scss" | "less" => ("/*", " */"),
+        _ if path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with(".env") || n == "Dockerfile" || n == "Makefile") => ("#", ""),
+        _ => ("//", ""),
+    }
+}
+
+/// Convert a byte offset into an LSP (line, UTF-16 character) position.
+pub fn position_at(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (line, before[line_start..].encode_utf16().count())
+}
+
+fn workspace_root(params: &Value) -> Option<PathBuf> {
+    params["rootUri"].as_str().map(uri_to_path)
+        .or_else(|| params["workspaceFolders"].as_array().and_then(|f| f.first()).and_then(|f| f["uri"].as_str()).map(uri_to_path))
+        .or_else(|| params["rootPath"].as_str().map(PathBuf::from))
+}
+
+fn uri_to_path(uri: &str) -> PathBuf {
+    let raw = uri.strip_prefix("file://").unwrap_or(uri);
+    let bytes = raw.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            // hex digits are ASCII, so anything else stays as it is
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|h| u8::from_str_radix(h, 16).ok());
+            if let Some(b) = hex { out.push(b); i += 3; continue; }
+        }
+        out.push(bytes[i]); i += 1;
+    }
+    PathBuf::from(String::from_utf8_lossy(&out).into_owned())
+}
+
+async fn read_message<R: AsyncBufRead + Unpin>(r: &mut R) -> Result<Option<Value>> {
+    let mut len: Option<usize> = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if r.read_line(&mut line).await? == 0 { return Ok(None); }
+        let h = line.trim_end();
+        if h.is_empty() { break; }
+        if let Some(v) = h.strip_prefix("Content-Length:") { len = Some(v.trim().parse().context("bad Content-Length")?); }
+    }
+    let mut buf = vec![0; len.context("missing Content-Length")?];
+    r.read_exact(&mut buf).await?;
+    Ok(Some(serde_json::from_slice(&buf)?))
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(w: &mut W, msg: &Value) -> Result<()> {
+    let body = msg.to_string();
+    w.write_all(format!("Content-Length: {}\r\n\r\n", body.len()).as_bytes()).await?;
+    w.write_all(body.as_bytes()).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+async fn reply<W: AsyncWrite + Unpin>(w: &mut W, id: Option<Value>, result: Value) -> Result<()> {
+    write_message(w, &json!({ "jsonrpc": "2.0", "id": id.unwrap_or(Value::Null), "result": result })).await
+}
+
+async fn notify<W: AsyncWrite + Unpin>(w: &mut W, method: &str, params: Value) -> Result<()> {
+    write_message(w, &json!({ "jsonrpc": "2.0", "method": method, "params": params })).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ValveConfig, compile};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn personas(config_str: &str) -> (TempDir, Vec<CompiledPersona>) {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let sage_dir = temp_dir.path().join(".sage");
+        std::fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::write(sage_dir.join("valve.yml"), config_str).expect("Failed to write config file");
+        let config = ValveConfig::load_from_repo(temp_dir.path()).expect("Failed to load config");
+        let compiled = compile(&config).expect("Failed to compile personas");
+        (temp_dir, compiled)
+    }
+
+    #[test]
+    fn test_diagnostics_for_trigger_hits() {
+        let (temp_dir, compiled) = personas(r#"
+personas:
+  TypeNazi:
+    filters: ["**/*.ts"]
+    triggers: ["as any"]
+    response: "strict-type-enforcement"
+    severity: "high"
+"#);
+        let text = "const a = 1;\nconst b = x as any;\n";
+        let diags = diagnostics(&compiled, temp_dir.path(), Path::new("src/a.ts"), text);
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0]["range"]["start"]["line"], 1);
+        assert_eq!(diags[0]["range"]["start"]["character"], 12);
+        assert_eq!(diags[0]["severity"], SEV_ERROR);
+        assert_eq!(diags[0]["message"], "strict-type-enforcement");
+    }
+
+    #[test]
+    fn test_suppression_comment_silences_persona() {
+        let (temp_dir, compiled) = personas(r#"
+personas:
+  TypeNazi:
+    filters: ["**/*.ts"]
+    triggers: ["as any"]
+"#);
+        let text = "// sage-valve: ignore TypeNazi\nconst b = x as any;\n";
+        assert!(diagnostics(&compiled, temp_dir.path(), Path::new("a.ts"), text).is_empty());
+        let text = "// sage-valve: ignore SomeoneElse\nconst b = x as any;\n";
+        assert_eq!(diagnostics(&compiled, temp_dir.path(), Path::new("a.ts"), text).len(), 1);
+    }
+
+    #[test]
+    fn test_code_action_inserts_comment_above_hit() {
+        let diag = json!({ "source": SOURCE, "code": "TypeNazi", "range": { "start": { "line": 1, "character": 14 } } });
+        let actions = code_actions("file:///repo/a.py", "x = 1\n    y = cast(Any)\n", &[diag]);
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0]["edit"]["changes"]["file:///repo/a.py"][0]["newText"], "    # sage-valve: ignore TypeNazi\n");
+    }
+
+    #[test]
+    fn test_position_and_uri_helpers() {
+        assert_eq!(position_at("ab\ncdé f", 9), (1, 5));
+        assert_eq!(uri_to_path("file:///tmp/my%20repo/a.rs"), PathBuf::from("/tmp/my repo/a.rs"));
+        assert_eq!(uri_to_path("file:///a%é"), PathBuf::from("/a%é"));
+        assert_eq!(uri_to_path("file:///a%2"), PathBuf::from("/a%2"));
+        assert_eq!(uri_to_path("file:///a%+1"), PathBuf::from("/a%+1"));
+    }
+
+    #[tokio::test]
+    async fn test_framing_roundtrip() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &json!({ "jsonrpc": "2.0", "method": "exit" })).await.unwrap();
+        let mut r = BufReader::new(buf.as_slice());
+        let msg = read_message(&mut r).await.unwrap().unwrap();
+        assert_eq!(msg["method"], "exit");
+        assert!(read_message(&mut r).await.unwrap().is_none());
+    }
+}
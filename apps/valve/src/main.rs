@@ -1,36 +1,152 @@
 use clap::{Parser, Subcommand};
-use tracing_subscriber::{fmt, EnvFilter};
+use tracing_subscriber::{fmt, prelude::*, reload, EnvFilter};
 use anyhow::Result;
+use std::path::PathBuf;
+use severity::Severity;
 
 mod daemon;
 mod supervisor;
 mod state;
 mod config;
+mod condition;
+mod schedule;
+mod trigger;
+mod catalog;
+mod init;
+mod lint;
 mod persona;
 mod watch;
 mod control;
 mod service;
+mod lsp;
+mod severity;
+mod sink;
+mod halt;
+mod git;
+mod hook;
+mod replay;
+mod paths;
+mod discover;
+mod settings;
 
 #[derive(Parser)]
 #[command(name = "sage-valve", version, about = "SAGE perceptual valve daemon")]
 struct Cli {
     #[command(subcommand)]
     cmd: Command,
-    /// Override control-plane port (localhost)
-    #[arg(long, global = true, default_value_t = 5576)]
-    port: u16,
+    /// Override control-plane port (localhost; defaults to the running daemon's)
+    #[arg(long, global = true)]
+    port: Option<u16>,
+    /// Keep registry, halts, chronicle and lock under this directory
+    #[arg(long, global = true, env = "SAGE_VALVE_HOME")]
+    state_dir: Option<PathBuf>,
+    /// Run or talk to a separate named valve instance
+    #[arg(long, global = true, env = "SAGE_VALVE_INSTANCE")]
+    instance: Option<String>,
 }
 
 #[derive(Subcommand)]
 enum Command {
     /// Run the valve in the foreground (supervised)
-    Run,
+    ///
+    /// Settings come from flags, then SAGE_VALVE_* env vars (RUST_LOG for
+    /// the log filter), then valve.toml in the config dir, then defaults.
+    Run {
+        #[command(flatten)]
+        overrides: settings::Overrides,
+    },
+    /// Inspect daemon settings and persona configs
+    Config {
+        #[command(subcommand)]
+        cmd: ConfigCommand,
+    },
+    /// Write a starter .sage/valve.yml with builtin personas suited to the repo
+    Init {
+        #[arg(default_value = ".")]
+        repo: PathBuf,
+        /// Replace an existing .sage/valve.yml
+        #[arg(long)]
+        force: bool,
+    },
+    /// Browse the builtin personas
+    Personas {
+        #[command(subcommand)]
+        cmd: PersonasCommand,
+    },
     /// Register a codebase to watch
-    Register { path: String },
-    /// Unregister a codebase by ID or path
+    Register {
+        #[arg(required_unless_present = "discover")]
+        path: Option<String>,
+        /// How to handle a path inside (or enclosing) a registered codebase
+        #[arg(long, value_enum, default_value_t = state::OverlapPolicy::Reject)]
+        on_overlap: state::OverlapPolicy,
+        /// Register every git repo under this directory instead
+        #[arg(long, conflicts_with = "path")]
+        discover: Option<PathBuf>,
+        /// Directory levels below the discovery root to search
+        #[arg(long, default_value_t = 3, requires = "discover")]
+        depth: usize,
+        /// Only register repos that have .sage/valve.yml
+        #[arg(long, requires = "discover")]
+        require_config: bool,
+        /// Keep watching the root: register new clones and unregister repos that
+        /// stop qualifying (deleted ones go after `--forget-missing-after`)
+        #[arg(long, requires = "discover")]
+        watch: bool,
+    },
+    /// Unregister a codebase by ID or path (or stop watching a discovery root)
     Unregister { target: String },
     /// List registered codebases
     List,
+    /// Show what the daemon is doing with each codebase (watching, missing, ...)
+    Status,
+    /// Make the daemon re-read its registry, persona configs and settings (like SIGHUP)
+    Reload,
+    /// Show a codebase by id, alias, path or id prefix
+    Show { target: String },
+    /// Edit a codebase's alias, tags, enabled flag or overrides
+    Edit {
+        target: String,
+        /// Human-friendly name usable wherever an id is accepted
+        #[arg(long, conflicts_with = "no_alias")]
+        alias: Option<String>,
+        /// Remove the alias
+        #[arg(long)]
+        no_alias: bool,
+        /// Add a tag (repeatable)
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Remove a tag (repeatable)
+        #[arg(long = "untag")]
+        untags: Vec<String>,
+        /// Resume watching
+        #[arg(long, conflicts_with = "disable")]
+        enable: bool,
+        /// Stop watching without unregistering
+        #[arg(long)]
+        disable: bool,
+        /// Override the persona config file (relative to the codebase root)
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// Override the chronicle file for this codebase (relative to the current directory)
+        #[arg(long)]
+        chronicle: Option<PathBuf>,
+        /// Override the chronicle severity floor for this codebase
+        #[arg(long)]
+        min_severity: Option<Severity>,
+        /// Override the debounce window for this codebase
+        #[arg(long)]
+        debounce_ms: Option<u64>,
+        /// Drop all overrides before applying the ones given
+        #[arg(long)]
+        clear_overrides: bool,
+    },
+    /// Stream live valve events from the running daemon
+    Subscribe {
+        /// Only show events at or above this severity
+        #[arg(long)]
+        min_severity: Option<Severity>,
+    },
     /// Install as OS service/agent (prints what it did)
     Install,
     /// Uninstall OS service/agent
@@ -39,25 +155,195 @@ enum Command {
     Start,
     /// Stop service (if installed)
     Stop,
+    /// Exit non-zero while a HALT_EVERYTHING hit has halted the codebase
+    Gate {
+        /// Codebase path, or a registered id, alias or id prefix
+        repo: PathBuf,
+    },
+    /// Clear a codebase halt, recording who cleared it and why
+    Ack {
+        /// Codebase path, or a registered id, alias or id prefix
+        repo: PathBuf,
+        /// Why the halt is safe to clear
+        #[arg(long)]
+        reason: String,
+        /// Who is clearing it (defaults to $USER)
+        #[arg(long, env = "USER")]
+        by: String,
+    },
+    /// Git pre-commit integration
+    Hook {
+        #[command(subcommand)]
+        cmd: HookCommand,
+    },
+    /// Evaluate the current personas over a range of git history
+    Replay {
+        /// Codebase path, or a registered id, alias or id prefix
+        repo: PathBuf,
+        /// Start of the range; its own changes are not replayed
+        #[arg(long)]
+        from: String,
+        /// Inclusive end of the range
+        #[arg(long, default_value = "HEAD")]
+        to: String,
+        /// Write events as NDJSON to this file instead of printing a report
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// Append events to the live chronicle
+        #[arg(long, conflicts_with = "out")]
+        chronicle: bool,
+        /// Drop events below this severity
+        #[arg(long, default_value_t = Severity::Info)]
+        min_severity: Severity,
+    },
+    /// Serve persona hits as diagnostics over LSP (stdio)
+    Lsp {
+        /// Only publish diagnostics at or above this severity
+        #[arg(long, default_value_t = Severity::Info)]
+        min_severity: Severity,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the effective settings and where each one comes from
+    Show,
+    /// Validate persona configs the way the daemon does; exits non-zero on errors
+    Check {
+        /// Repos (using .sage/valve.yml) or config files
+        #[arg(default_value = ".")]
+        paths: Vec<PathBuf>,
+        /// One JSON diagnostic per line instead of file:line:col text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Warn about personas that can never fire or are expensive to run,
+    /// checking globs against the repo's files
+    Lint {
+        #[arg(default_value = ".")]
+        repo: PathBuf,
+        /// One JSON warning per line instead of file:line:col text
+        #[arg(long)]
+        json: bool,
+        /// Exit non-zero if there are any warnings (for CI)
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Show each persona in effect and where it and its fields come from
+    /// (layers, includes and `extends`)
+    Explain {
+        #[arg(default_value = ".")]
+        repo: PathBuf,
+        /// Only this persona
+        #[arg(long)]
+        persona: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum PersonasCommand {
+    /// List the builtin catalog, usable as `use: builtin/<name>`
+    Catalog {
+        /// Catalog version (default: the newest)
+        #[arg(long)]
+        version: Option<u32>,
+    },
+}
+
+#[derive(Subcommand)]
+enum HookCommand {
+    /// Install a pre-commit hook (chaining any existing one)
+    Install {
+        /// Codebase path, or a registered id, alias or id prefix
+        #[arg(default_value = ".")]
+        repo: PathBuf,
+        /// Block commits on hits at or above this severity
+        #[arg(long, default_value_t = Severity::High)]
+        min_severity: Severity,
+    },
+    /// Evaluate personas against staged content (what the hook runs)
+    Run {
+        /// Codebase path, or a registered id, alias or id prefix
+        #[arg(default_value = ".")]
+        repo: PathBuf,
+        /// Block commits on hits at or above this severity
+        #[arg(long, env = "SAGE_VALVE_HOOK_THRESHOLD", default_value_t = Severity::High)]
+        min_severity: Severity,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // logging
-    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
-    fmt().with_env_filter(filter).init();
+    // logging (stderr, so stdout stays free for protocol traffic)
+    // (reloadable, so a running daemon can pick up a new level)
+    let log_filter = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_else(|_| "info".into());
+    let (filter, log_handle) = reload::Layer::new(EnvFilter::try_new(&log_filter).unwrap_or_else(|_| EnvFilter::new("info")));
+    tracing_subscriber::registry().with(filter).with(fmt::layer().with_writer(std::io::stderr)).init();
 
     let cli = Cli::parse();
+    let paths = paths::Paths::resolve(cli.state_dir.as_deref(), cli.instance.as_deref())?;
+    let port = cli.port.or_else(|| paths.published_port()).unwrap_or(paths::DEFAULT_PORT);
+    let named = cli.instance.is_some();
+    let settings = |mut o: settings::Overrides| { o.port = cli.port; settings::Settings::load(&paths, named, &o) };
+    // registered codebases can be named by id or alias as well as by path
+    let codebase = |t: &PathBuf| state::Registry::path_of(&paths.registry(), t);
 
     match cli.cmd {
-        Command::Run => daemon::run_foreground(cli.port).await?,
-        Command::Register { path } => control::client_register(cli.port, path).await?,
-        Command::Unregister { target } => control::client_unregister(cli.port, target).await?,
-        Command::List => control::client_list(cli.port).await?,
+        Command::Run { overrides } => {
+            let initial = settings(overrides.clone())?;
+            if initial.log != log_filter { log_handle.reload(EnvFilter::try_new(&initial.log)?)?; }
+            let (reload_paths, port) = (paths.clone(), cli.port);
+            let reloader = daemon::Reloader {
+                settings: Box::new(move || settings::Settings::load(&reload_paths, named, &settings::Overrides { port, ..overrides.clone() })),
+                set_log_filter: Box::new(move |f| Ok(log_handle.reload(EnvFilter::try_new(f)?)?)),
+            };
+            daemon::run_foreground(&paths, reloader).await?
+        }
+        Command::Config { cmd: ConfigCommand::Show } => print!("{}", settings(Default::default())?.show()),
+        Command::Config { cmd: ConfigCommand::Check { paths, json } } => config::check_files(&paths, json, config::user_config().as_deref())?,
+        Command::Config { cmd: ConfigCommand::Lint { repo, json, strict } } => lint::lint_repo(&repo, json, strict, config::user_config().as_deref())?,
+        Command::Config { cmd: ConfigCommand::Explain { repo, persona } } => print!("{}", config::explain(&repo, persona.as_deref(), config::user_config().as_deref())?),
+        Command::Init { repo, force } => println!("wrote {}", init::init(&repo, force)?.display()),
+        Command::Personas { cmd: PersonasCommand::Catalog { version } } => print!("{}", catalog::describe(version)?),
+        Command::Register { on_overlap, discover: Some(root), depth, require_config, watch, .. } => {
+            let root = discover::DiscoveryRoot { path: root.canonicalize()?, depth, require_config, on_overlap };
+            control::client_discover(port, root, watch).await?
+        }
+        Command::Register { path, on_overlap, discover: None, .. } => control::client_register(port, path.unwrap_or_default(), on_overlap).await?,
+        Command::Unregister { target } => control::client_unregister(port, target).await?,
+        Command::List => control::client_list(port).await?,
+        Command::Status => control::client_status(port).await?,
+        Command::Reload => control::client_reload(port).await?,
+        Command::Show { target } => control::client_show(port, target).await?,
+        Command::Edit { target, alias, no_alias, tags, untags, enable, disable, config, chronicle, min_severity, debounce_ms, clear_overrides } => {
+            let patch = state::CodebasePatch {
+                alias,
+                clear_alias: no_alias,
+                add_tags: tags,
+                remove_tags: untags,
+                enabled: if enable { Some(true) } else if disable { Some(false) } else { None },
+                overrides: state::Overrides { config, chronicle: chronicle.map(std::path::absolute).transpose()?, min_severity, debounce_ms },
+                clear_overrides,
+            };
+            control::client_edit(port, target, patch).await?
+        }
+        Command::Subscribe { min_severity } => control::client_subscribe(port, min_severity).await?,
         Command::Install => service::install_service()?,
         Command::Uninstall => service::uninstall_service()?,
         Command::Start => service::start_service()?,
         Command::Stop => service::stop_service()?,
+        Command::Gate { repo } => halt::gate(&halt::HaltStore::at(paths.halts()), &codebase(&repo))?,
+        Command::Ack { repo, reason, by } => halt::ack(&halt::HaltStore::at(paths.halts()), &settings(Default::default())?.chronicle, &codebase(&repo), &by, &reason).await?,
+        Command::Hook { cmd: HookCommand::Install { repo, min_severity } } => {
+            println!("installed {}", hook::install(&codebase(&repo), min_severity)?.display());
+        }
+        Command::Hook { cmd: HookCommand::Run { repo, min_severity } } => hook::run(&codebase(&repo), min_severity, config::user_config().as_deref())?,
+        Command::Replay { repo, from, to, out, chronicle, min_severity } => {
+            let out = if chronicle { Some(settings(Default::default())?.chronicle) } else { out };
+            let events = replay::replay(&codebase(&repo), &from, &to, config::user_config().as_deref())?;
+            replay::emit(&events, out.as_deref(), min_severity).await?;
+        }
+        Command::Lsp { min_severity } => lsp::run_stdio(min_severity, config::user_config()).await?,
     }
 
     Ok(())
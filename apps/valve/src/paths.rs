@@ -0,0 +1,76 @@
+use anyhow::{bail, Context, Result};
+use directories::ProjectDirs;
+use std::path::{Path, PathBuf};
+
+/// Control-plane port of the default instance.
+pub const DEFAULT_PORT: u16 = 5576;
+
+/// Where one valve instance keeps its settings, registry, lock, control
+/// address, halt state and chronicle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Paths {
+    pub config: PathBuf,
+    pub data: PathBuf,
+    pub runtime: PathBuf,
+}
+
+impl Paths {
+    /// Keep everything under `dir` (tests, `--state-dir`).
+    pub fn at(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        Self { config: dir.clone(), data: dir.clone(), runtime: dir }
+    }
+
+    /// Resolve from `--state-dir`/`SAGE_VALVE_HOME` (or the platform dirs)
+    /// and an optional instance name, which gets its own subdirectory.
+    pub fn resolve(state_dir: Option<&Path>, instance: Option<&str>) -> Result<Self> {
+        let base = match state_dir {
+            Some(d) => Self::at(d),
+            None => {
+                let d = ProjectDirs::from("dev","sage","valve").context("dirs")?;
+                Self { config: d.config_dir().to_path_buf(), data: d.data_dir().to_path_buf(), runtime: d.runtime_dir().unwrap_or(d.data_dir()).to_path_buf() }
+            }
+        };
+        let Some(name) = instance else { return Ok(base) };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            bail!("invalid instance name '{}' (use letters, digits, '-' and '_')", name);
+        }
+        let sub = |d: PathBuf| d.join("instances").join(name);
+        Ok(Self { config: sub(base.config), data: sub(base.data), runtime: sub(base.runtime) })
+    }
+
+    /// Daemon settings file.
+    pub fn settings(&self) -> PathBuf { self.config.join("valve.toml") }
+    pub fn registry(&self) -> PathBuf { self.data.join("registry.json") }
+    pub fn halts(&self) -> PathBuf { self.data.join("halts.json") }
+    /// When each scheduled persona last swept
+    pub fn schedules(&self) -> PathBuf { self.data.join("schedules.json") }
+    pub fn chronicle(&self) -> PathBuf { self.data.join("chronicles").join("valve.ndjson") }
+    pub fn lockfile(&self) -> PathBuf { self.runtime.join("valve.lock") }
+    /// Written by a running daemon so clients can find its control port.
+    pub fn control_addr(&self) -> PathBuf { self.runtime.join("control.addr") }
+
+    /// Port of the running daemon, if it published one.
+    pub fn published_port(&self) -> Option<u16> {
+        let raw = std::fs::read_to_string(self.control_addr()).ok()?;
+        raw.trim().rsplit(':').next()?.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_instances_are_separate() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let default = Paths::resolve(Some(temp_dir.path()), None).unwrap();
+        let work = Paths::resolve(Some(temp_dir.path()), Some("work")).unwrap();
+        assert_eq!(default.registry(), temp_dir.path().join("registry.json"));
+        assert_eq!(work.registry(), temp_dir.path().join("instances/work/registry.json"));
+        assert_ne!(default.lockfile(), work.lockfile());
+        assert_ne!(default.chronicle(), work.chronicle());
+        assert!(Paths::resolve(Some(temp_dir.path()), Some("../escape")).is_err());
+    }
+}
@@ -1,33 +1,170 @@
-use crate::config::CompiledPersona;
-use serde::Serialize;
-use std::path::Path;
+use crate::{config::CompiledPersona, git, severity::Severity};
+use chrono::{DateTime, Datelike, Local, Timelike};
+use serde::{Deserialize, Serialize};
+use std::{cell::OnceCell, fmt, path::Path};
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Clone, Default)]
 pub struct ValveEvent {
     pub persona: String,
     pub repo: String,
     pub file: String,
     pub reason: String,
+    pub severity: Severity,
     pub timestamp: i64,
+    /// Config layer the persona came from (`user`, `.sage/valve.yml`, ...)
+    pub layer: String,
+    /// Commit the event was evaluated at (set by `replay`)
+    #[serde(rename = "graphCommit", skip_serializing_if = "Option::is_none")]
+    pub graph_commit: Option<String>,
+    /// What happened to `file`; unset for sweeps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<Change>,
+    /// Both ends of a rename (`to` is also `file`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    /// The trigger clause that fired (`"as any" ×7`, `all(...)`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trigger: Option<String>,
+    /// Every file a scheduled sweep matched (`file` is then `.`)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
 }
 
-pub fn match_personas<'a>(personas: &'a [CompiledPersona], repo: &Path, rel: &Path, content: Option<&str>) -> Vec<ValveEvent> {
-    let mut events = vec![];
-    for p in personas {
-        if !p.globset.is_empty() && !p.globset.is_match(rel) { continue; }
-        let mut reasons = vec!["glob".to_string()];
-        if !p.triggers.is_empty() {
-            if let Some(text) = content { if p.triggers.iter().any(|re| re.is_match(text)) { reasons.push("trigger".into()); } else { continue; } }
-        }
-        events.push(ValveEvent {
-            persona: p.name.clone(),
-            repo: repo.display().to_string(),
-            file: rel.display().to_string(),
-            reason: reasons.join("+"),
-            timestamp: chrono::Utc::now().timestamp_millis(),
-        });
+/// What happened to a path; personas pick the kinds they react to with `on:`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Create,
+    #[default]
+    Modify,
+    Delete,
+    Rename,
+}
+
+impl Change {
+    /// Without `on:`, a persona reacts to every kind of change.
+    pub const DEFAULT_ON: &'static [Change] = &[Change::Create, Change::Modify, Change::Delete, Change::Rename];
+    /// Without `on:`, a persona with triggers judges content that is there
+    /// after the change, so it leaves deletes alone.
+    pub const DEFAULT_ON_TRIGGERS: &'static [Change] = &[Change::Create, Change::Modify, Change::Rename];
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self { Change::Create => "create", Change::Modify => "modify", Change::Delete => "delete", Change::Rename => "rename" })
+    }
+}
+
+/// Everything `conditions:` can ask about one change. Facts that need git
+/// or the filesystem are looked up on first use.
+pub struct Facts<'a> {
+    pub repo: &'a Path,
+    pub rel: &'a Path,
+    pub content: Option<&'a str>,
+    pub change: Change,
+    /// Old path of a rename
+    pub from: Option<&'a Path>,
+    /// When the change happened (commit time in `replay`)
+    pub now: DateTime<Local>,
+    size: OnceCell<Option<u64>>,
+    branch: OnceCell<Option<String>>,
+    tracked: OnceCell<bool>,
+}
+
+impl<'a> Facts<'a> {
+    pub fn new(repo: &'a Path, rel: &'a Path, content: Option<&'a str>) -> Self {
+        Self { repo, rel, content, change: Change::Modify, from: None, now: Local::now(), size: OnceCell::new(), branch: OnceCell::new(), tracked: OnceCell::new() }
+    }
+
+    pub fn with_change(self, change: Change) -> Self { Self { change, ..self } }
+    pub fn renamed_from(self, from: &'a Path) -> Self { Self { change: Change::Rename, from: Some(from), ..self } }
+    pub fn at(self, now: DateTime<Local>) -> Self { Self { now, ..self } }
+    /// Take the branch as already known instead of asking git.
+    pub fn on_branch(self, branch: Option<String>) -> Self { let _ = self.branch.set(branch); self }
+    /// Take `tracked` as already known instead of asking git.
+    pub fn with_tracked(self, tracked: bool) -> Self { let _ = self.tracked.set(tracked); self }
+
+    /// Bytes, from the content or else the file on disk.
+    pub fn size(&self) -> Option<u64> {
+        *self.size.get_or_init(|| self.content.map(|c| c.len() as u64).or_else(|| std::fs::metadata(self.repo.join(self.rel)).ok().map(|m| m.len())))
+    }
+    pub fn lines(&self) -> Option<u64> { self.content.map(|c| c.lines().count() as u64) }
+    pub fn hour(&self) -> u64 { self.now.hour() as u64 }
+    pub fn weekday(&self) -> String { self.now.weekday().to_string().to_lowercase() }
+    pub fn is_new(&self) -> bool { self.change == Change::Create }
+
+    pub fn branch(&self) -> Option<String> { self.branch.get_or_init(|| branch(self.repo)).clone() }
+    pub fn tracked(&self) -> bool { *self.tracked.get_or_init(|| tracked(self.repo, self.rel)) }
+}
+
+/// The branch checked out in `repo`, if it is a git checkout.
+pub fn branch(repo: &Path) -> Option<String> {
+    git::git_str(repo, &["rev-parse", "--abbrev-ref", "HEAD"]).ok()
+}
+
+/// Whether git tracks `rel` in `repo`.
+pub fn tracked(repo: &Path, rel: &Path) -> bool {
+    git::git(repo, &["ls-files", "--error-unmatch", "--", &rel.to_string_lossy()]).is_ok()
+}
+
+pub fn match_personas(personas: &[CompiledPersona], repo: &Path, rel: &Path, content: Option<&str>) -> Vec<ValveEvent> {
+    match_facts(personas, &Facts::new(repo, rel, content))
+}
+
+/// Personas listening for `facts.change` whose globs, triggers and
+/// conditions all hold. Scheduled personas only fire from their sweeps.
+pub fn match_facts(personas: &[CompiledPersona], facts: &Facts) -> Vec<ValveEvent> {
+    let file = facts.rel.display().to_string();
+    personas.iter().filter(|p| p.schedule.is_none() && p.on.contains(&facts.change)).filter_map(|p| {
+        let (reason, trigger) = matches(p, facts)?;
+        Some(ValveEvent {
+            reason,
+            trigger,
+            kind: Some(facts.change),
+            from: facts.from.map(|f| f.display().to_string()),
+            to: facts.from.map(|_| file.clone()),
+            ..event(p, facts.repo, &file)
+        })
+    }).collect()
+}
+
+/// Why `p` fires for `facts` (`glob+trigger`, `delete+trigger`, ...) and
+/// the trigger clause that held, if it fires. Triggers need content: with
+/// none (binary, or deleted and never committed) a trigger persona does not
+/// fire. A delete's triggers are judged against the removed content.
+pub fn matches(p: &CompiledPersona, facts: &Facts) -> Option<(String, Option<String>)> {
+    if !p.applies_to(facts.rel) || (!p.globset.is_empty() && !p.globset.is_match(facts.rel)) { return None; }
+    let mut reasons = vec![if facts.change == Change::Delete { "delete" } else { "glob" }];
+    let mut clause = None;
+    if let Some(rule) = &p.rule {
+        clause = Some(rule.eval(facts.content?)?);
+        reasons.push("trigger");
+    }
+    if !p.conditions.is_empty() {
+        if !p.conditions.iter().all(|c| c.eval(facts)) { return None; }
+        reasons.push("conditions");
+    }
+    Some((reasons.join("+"), clause))
+}
+
+/// The one event a scheduled sweep emits for everything it matched.
+pub fn sweep_event(p: &CompiledPersona, repo: &Path, files: Vec<String>) -> ValveEvent {
+    let reason = format!("sweep({}): {} file{}", p.schedule.as_ref().map(|s| s.to_string()).unwrap_or_default(), files.len(), if files.len() == 1 { "" } else { "s" });
+    ValveEvent { reason, files, ..event(p, repo, ".") }
+}
+
+fn event(p: &CompiledPersona, repo: &Path, file: &str) -> ValveEvent {
+    ValveEvent {
+        persona: p.name.clone(),
+        repo: repo.display().to_string(),
+        file: file.to_string(),
+        severity: p.severity,
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        layer: p.layer.clone(),
+        ..Default::default()
     }
-    events
 }
 
 #[cfg(test)]
@@ -142,4 +279,78 @@ personas:
         let events = match_personas(&compiled, repo, rel, content);
         assert_eq!(events.len(), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_conditions_gate_a_match() {
+        let config_str = r#"
+personas:
+  NewBigFiles:
+    filters: ["**/*.rs"]
+    conditions: ["kind == create && lines > 2"]
+"#;
+
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let sage_dir = temp_dir.path().join(".sage");
+        std::fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::write(sage_dir.join("valve.yml"), config_str).expect("Failed to write config file");
+
+        let config = ValveConfig::load_from_repo(temp_dir.path()).expect("Failed to load config");
+        let compiled = compile(&config).expect("Failed to compile personas");
+
+        let repo = temp_dir.path();
+        let rel = Path::new("lib.rs");
+        let content = Some("fn a() {}\nfn b() {}\nfn c() {}\n");
+        assert!(match_facts(&compiled, &Facts::new(repo, rel, content)).is_empty());
+        assert!(match_facts(&compiled, &Facts::new(repo, rel, Some("fn a() {}\n")).with_change(Change::Create)).is_empty());
+        let events = match_facts(&compiled, &Facts::new(repo, rel, content).with_change(Change::Create));
+        assert_eq!(events.len(), 1);
+        assert!(events[0].reason.contains("conditions"));
+    }
+
+    #[test]
+    fn test_on_selects_kinds_and_renames_carry_both_paths() {
+        let config_str = r#"
+personas:
+  Default:
+    filters: ["**/*.rs"]
+  Deletes:
+    filters: ["**/*.rs"]
+    on: [delete, rename]
+  Edits:
+    filters: ["**/*.rs"]
+    on: [modify]
+  Unsafe:
+    filters: ["**/*.rs"]
+    triggers: ["unsafe"]
+  RemovedUnsafe:
+    filters: ["**/*.rs"]
+    triggers: ["unsafe"]
+    on: [delete]
+"#;
+
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let sage_dir = temp_dir.path().join(".sage");
+        std::fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::write(sage_dir.join("valve.yml"), config_str).expect("Failed to write config file");
+
+        let config = ValveConfig::load_from_repo(temp_dir.path()).expect("Failed to load config");
+        let compiled = compile(&config).expect("Failed to compile personas");
+
+        let repo = temp_dir.path();
+        let fired = |facts: &Facts| { let mut v: Vec<_> = match_facts(&compiled, facts).into_iter().map(|e| e.persona).collect(); v.sort(); v };
+        assert_eq!(fired(&Facts::new(repo, Path::new("a.rs"), None)), vec!["Default", "Edits"]);
+        assert_eq!(fired(&Facts::new(repo, Path::new("a.rs"), None).with_change(Change::Delete)), vec!["Default", "Deletes"]);
+        // trigger personas leave deletes alone unless they ask, and then judge the removed content
+        let removed = Facts::new(repo, Path::new("a.rs"), Some("unsafe {}")).with_change(Change::Delete);
+        assert_eq!(fired(&removed), vec!["Default", "Deletes", "RemovedUnsafe"]);
+        let events = match_facts(&compiled, &removed);
+        assert_eq!(events.iter().find(|e| e.persona == "RemovedUnsafe").map(|e| e.reason.as_str()), Some("delete+trigger"));
+        assert_eq!(events.iter().find(|e| e.persona == "Default").map(|e| e.reason.as_str()), Some("delete"));
+        assert_eq!(fired(&Facts::new(repo, Path::new("a.rs"), Some("unsafe {}"))), vec!["Default", "Edits", "Unsafe"]);
+
+        let events = match_facts(&compiled, &Facts::new(repo, Path::new("new.rs"), None).renamed_from(Path::new("old.rs")));
+        assert_eq!(events.len(), 2);
+        let json = serde_json::to_value(&events[0]).unwrap();
+        assert_eq!((json["kind"].as_str(), json["from"].as_str(), json["to"].as_str()), (Some("rename"), Some("old.rs"), Some("new.rs")));
+    }
+}
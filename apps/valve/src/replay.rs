@@ -0,0 +1,147 @@
+use crate::{config, git, persona::{self, Change, Facts, ValveEvent}, severity::Severity, sink::NdjsonSink};
+use chrono::TimeZone;
+use anyhow::{Context, Result};
+use std::{collections::BTreeMap, path::Path};
+
+/// Evaluate the current personas over every commit in `from..to` (so
+/// `from` itself is excluded), oldest first, stamping each event with the
+/// commit it was found at. History is followed by first parent: a merge is
+/// evaluated as everything it brings in, and the side branch's own commits
+/// are not visited. Conditions see the commit, not the checkout: `branch`
+/// is the branch `to` names (none for a bare commit) and every changed path
+/// is `tracked`. Submodules and blobs git cannot show have no content.
+/// `user` is the user config layer, if any.
+pub fn replay(repo: &Path, from: &str, to: &str, user: Option<&Path>) -> Result<Vec<ValveEvent>> {
+    let top = git::toplevel(repo)?;
+    let personas = config::load_layered(&top, &top.join(".sage/valve.yml"), user).context("replay needs .sage/valve.yml")?;
+    let range = format!("{}..{}", from, to);
+    let branch = git::git_str(&top, &["rev-parse", "--symbolic-full-name", to]).ok()
+        .and_then(|r| r.strip_prefix("refs/heads/").map(str::to_string));
+    let commits = git::git_str(&top, &["rev-list", "--reverse", "--topo-order", "--first-parent", &range])?;
+    let mut events = vec![];
+    for commit in commits.lines().filter(|l| !l.is_empty()) {
+        let when: i64 = git::git_str(&top, &["show", "-s", "--format=%ct", commit])?.parse().unwrap_or_default();
+        let changed = git::raw_changes(&git::git(&top, &["diff-tree", "--no-commit-id", "--root", "-r", "--raw", "-z", "-M", "-m", "--first-parent", commit])?);
+        let at = chrono::Local.timestamp_opt(when, 0).single().unwrap_or_else(chrono::Local::now);
+        for c in changed {
+            // a deleted path is evaluated as it was before the commit
+            let blob = match c.change {
+                Change::Delete => format!("{}^:{}", commit, c.path),
+                _ => format!("{}:{}", commit, c.path),
+            };
+            let text = if c.gitlink { None } else { git::git(&top, &["show", &blob]).ok().and_then(git::text) };
+            let facts = Facts::new(&top, Path::new(&c.path), text.as_deref()).with_change(c.change).at(at)
+                .on_branch(branch.clone()).with_tracked(true);
+            let facts = match &c.from { Some(from) => facts.renamed_from(Path::new(from)), None => facts };
+            for mut ev in persona::match_facts(&personas, &facts) {
+                ev.timestamp = when * 1000;
+                ev.graph_commit = Some(commit.to_string());
+                events.push(ev);
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// Write replayed events to `out` as NDJSON (the live chronicle only when
+/// explicitly chosen), or print a per-persona report when `out` is `None`.
+pub async fn emit(events: &[ValveEvent], out: Option<&Path>, min_severity: Severity) -> Result<()> {
+    let events: Vec<_> = events.iter().filter(|e| e.severity >= min_severity).collect();
+    if let Some(path) = out {
+        let mut sink = NdjsonSink::open(path, min_severity)?;
+        for ev in &events { sink.emit(ev).await?; }
+        sink.flush().await?;
+        println!("wrote {} event(s) to {}", events.len(), path.display());
+        return Ok(());
+    }
+    let mut by_persona: BTreeMap<&str, Vec<&ValveEvent>> = BTreeMap::new();
+    for ev in &events { by_persona.entry(ev.persona.as_str()).or_default().push(ev); }
+    for (name, hits) in by_persona {
+        println!("{} ({} hit(s))", name, hits.len());
+        for ev in hits {
+            let commit = ev.graph_commit.as_deref().unwrap_or_default();
+            println!("  {} [{}] {} ({})", &commit[..commit.len().min(10)], ev.severity, ev.file, ev.reason);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::testing;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_replay_stamps_commits() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let repo = temp_dir.path();
+        testing::init(repo);
+        fs::write(repo.join("a.ts"), "const x = 1;\n").unwrap();
+        let base = testing::commit_all(repo, "base");
+        fs::write(repo.join("a.ts"), "const x = y as any;\n").unwrap();
+        let bad = testing::commit_all(repo, "bad");
+        fs::write(repo.join("a.ts"), "const x = y;\n").unwrap();
+        testing::commit_all(repo, "fix");
+
+        // the persona is added after the fact; replay uses the current config
+        let sage_dir = repo.join(".sage");
+        std::fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::write(sage_dir.join("valve.yml"), r#"
+personas:
+  TypeNazi:
+    filters: ["**/*.ts"]
+    triggers: ["as any"]
+"#).expect("Failed to write config file");
+
+        let events = replay(repo, &base, "HEAD", None).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].graph_commit.as_deref(), Some(bad.as_str()));
+        let line = serde_json::to_string(&events[0]).unwrap();
+        assert!(line.contains("\"graphCommit\""));
+
+        // a merge is evaluated once, for what it brings into the mainline
+        git::git(repo, &["checkout", "-q", "-b", "side"]).unwrap();
+        fs::write(repo.join("b.ts"), "const z = w as any;\n").unwrap();
+        testing::commit_all(repo, "side");
+        git::git(repo, &["checkout", "-q", "-"]).unwrap();
+        git::git(repo, &["merge", "-q", "--no-ff", "-m", "merge", "side"]).unwrap();
+        let merge = git::git_str(repo, &["rev-parse", "HEAD"]).unwrap();
+        let events = replay(repo, &bad, "HEAD", None).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].file.as_str(), events[0].graph_commit.as_deref()), ("b.ts", Some(merge.as_str())));
+    }
+
+    #[test]
+    fn test_replay_describes_the_commit_and_skips_unreadable_paths() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let repo = temp_dir.path();
+        testing::init(repo);
+        let sage_dir = repo.join(".sage");
+        fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::write(sage_dir.join("valve.yml"), r#"
+personas:
+  Anything:
+    filters: ["**/*"]
+    conditions: ["tracked && branch == \"feature\""]
+"#).expect("Failed to write config file");
+        let base = testing::commit_all(repo, "base");
+        git::git(repo, &["checkout", "-q", "-b", "feature"]).unwrap();
+        fs::write(repo.join("gone.ts"), "x\n").unwrap();
+        git::git(repo, &["add", "gone.ts"]).unwrap();
+        git::git(repo, &["update-index", "--add", "--cacheinfo", &format!("160000,{base},sub")]).unwrap();
+        git::git(repo, &["commit", "-q", "-m", "add"]).unwrap();
+        let added = git::git_str(repo, &["rev-parse", "HEAD"]).unwrap();
+        // gone from the worktree and the index since, but tracked when it was committed
+        git::git(repo, &["rm", "-q", "--cached", "sub"]).unwrap();
+        fs::remove_file(repo.join("gone.ts")).unwrap();
+        testing::commit_all(repo, "remove");
+
+        let mut found: Vec<_> = replay(repo, &base, &added, None).unwrap().into_iter().map(|e| e.file).collect();
+        assert!(found.is_empty(), "a bare commit is on no branch: {found:?}");
+        found = replay(repo, &base, "feature", None).unwrap().into_iter().map(|e| e.file).collect();
+        found.sort();
+        assert_eq!(found, vec!["gone.ts", "gone.ts", "sub", "sub"]);
+    }
+}
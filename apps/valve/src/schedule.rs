@@ -0,0 +1,121 @@
+//! `schedule:` for personas that sweep their globs on a timer instead of
+//! reacting to changes. A schedule is a name (`hourly`, `daily`, `weekly`,
+//! `monthly`, `yearly`, optionally with a leading `@`) or a five-field cron
+//! expression (`minute hour day-of-month month day-of-week`) in local time.
+
+use crate::state;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use croner::Cron;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, fs, path::{Path, PathBuf}};
+
+const NAMED: &[&str] = &["hourly", "daily", "weekly", "monthly", "yearly"];
+
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    spec: String,
+    cron: Cron,
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(&self.spec) }
+}
+
+pub fn parse(src: &str) -> Result<Schedule, String> {
+    let spec = src.trim();
+    let name = spec.trim_start_matches('@');
+    let pattern = if NAMED.contains(&name) {
+        format!("@{}", name)
+    } else if spec.split_whitespace().count() == 5 {
+        spec.to_string()
+    } else {
+        return Err(format!("unknown schedule '{}' (use {} or a five-field cron expression)", spec, NAMED.join(", ")));
+    };
+    let cron = Cron::new(&pattern).parse().map_err(|e| format!("invalid cron expression '{}': {}", spec, e))?;
+    Ok(Schedule { spec: spec.to_string(), cron })
+}
+
+impl Schedule {
+    /// First run strictly after `t`.
+    pub fn next_after(&self, t: DateTime<Local>) -> Option<DateTime<Local>> {
+        self.cron.find_next_occurrence(&t, false).ok()
+    }
+
+    /// When to sweep next given the last run: `now` if a run was missed
+    /// since `last` (however many), otherwise the next slot.
+    pub fn due(&self, last: DateTime<Local>, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        match self.next_after(last)? {
+            t if t <= now => Some(now),
+            t => Some(t),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RunFile {
+    /// repo -> persona -> last sweep (or when the schedule was first seen)
+    #[serde(default)]
+    runs: BTreeMap<String, BTreeMap<String, DateTime<Utc>>>,
+}
+
+/// Persisted sweep history, so a schedule missed while the daemon was down
+/// runs once when it comes back.
+#[derive(Debug, Clone)]
+pub struct RunLog { path: PathBuf }
+
+impl RunLog {
+    pub fn at(path: PathBuf) -> Self { Self { path } }
+
+    pub fn last(&self, repo: &Path, persona: &str) -> Result<Option<DateTime<Local>>> {
+        Ok(self.read()?.runs.get(&key(repo)).and_then(|r| r.get(persona)).map(|t| t.with_timezone(&Local)))
+    }
+
+    pub fn record(&self, repo: &Path, persona: &str, at: DateTime<Local>) -> Result<()> {
+        state::with_lock(&self.path, || {
+            let mut file = self.read()?;
+            file.runs.entry(key(repo)).or_default().insert(persona.to_string(), at.with_timezone(&Utc));
+            state::write_atomic(&self.path, &serde_json::to_vec_pretty(&file)?)
+        })
+    }
+
+    fn read(&self) -> Result<RunFile> {
+        if !self.path.exists() { return Ok(RunFile::default()); }
+        serde_json::from_str(&fs::read_to_string(&self.path)?).with_context(|| format!("corrupt sweep history at {}", self.path.display()))
+    }
+}
+
+fn key(repo: &Path) -> String { repo.to_string_lossy().to_string() }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_named_and_cron_schedules() {
+        let at = |d, h, m| Local.with_ymd_and_hms(2024, 6, d, h, m, 0).unwrap();
+        assert_eq!(parse("daily").unwrap().next_after(at(1, 23, 30)), Some(at(2, 0, 0)));
+        assert_eq!(parse("@hourly").unwrap().next_after(at(1, 23, 30)), Some(at(2, 0, 0)));
+        assert_eq!(parse("*/15 9-17 * * 1-5").unwrap().next_after(at(1, 12, 0)), Some(at(3, 9, 0))); // Saturday -> Monday
+        assert!(parse("daily-cleanup").unwrap_err().contains("unknown schedule"));
+        assert!(parse("61 * * * *").unwrap_err().contains("invalid cron"));
+
+        // a missed run is due now, once; otherwise wait for the slot
+        let daily = parse("daily").unwrap();
+        assert_eq!(daily.due(at(1, 0, 0), at(4, 8, 0)), Some(at(4, 8, 0)));
+        assert_eq!(daily.due(at(4, 8, 0), at(4, 9, 0)), Some(at(5, 0, 0)));
+    }
+
+    #[test]
+    fn test_run_log_persists() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let log = RunLog::at(temp_dir.path().join("schedules.json"));
+        let (repo, t) = (Path::new("/repo"), Local.with_ymd_and_hms(2024, 6, 1, 0, 0, 0).unwrap());
+        assert_eq!(log.last(repo, "Janitor").unwrap(), None);
+        log.record(repo, "Janitor", t).unwrap();
+        assert_eq!(RunLog::at(temp_dir.path().join("schedules.json")).last(repo, "Janitor").unwrap(), Some(t));
+        assert_eq!(log.last(repo, "Other").unwrap(), None);
+    }
+}
@@ -20,7 +20,7 @@ WantedBy=multi-user.target
         std::process::Command::new("systemctl").args(["daemon-reload"]).status()?;
         std::process::Command::new("systemctl").args(["enable", "sage-valve"]).status()?;
         println!("installed systemd service");
-        return Ok(());
+        Ok(())
     }
     
     #[cfg(target_os = "macos")]
@@ -46,13 +46,13 @@ WantedBy=multi-user.target
         let path = format!("{}/Library/LaunchAgents/dev.sage.valve.plist", std::env::var("HOME")?);
         std::fs::write(&path, plist)?;
         println!("installed launchd plist at {}", path);
-        return Ok(());
+        Ok(())
     }
     
     #[cfg(target_os = "windows")]
     {
         println!("Please register as a Windows service (stub). For dev: use Task Scheduler or run foreground.");
-        return Ok(());
+        Ok(())
     }
     
     #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
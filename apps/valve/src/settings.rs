@@ -0,0 +1,250 @@
+use crate::{config, paths::{Paths, DEFAULT_PORT}, severity::Severity, supervisor::RestartPolicy};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::{Path, PathBuf}, str::FromStr};
+
+/// Where an effective setting came from. Precedence, highest first:
+/// command-line flag, environment variable, `valve.toml`, built-in default.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Env(&'static str),
+    Flag(&'static str),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(p) => write!(f, "{}", p.display()),
+            Source::Env(var) => write!(f, "env {}", var),
+            Source::Flag(flag) => write!(f, "{}", flag),
+        }
+    }
+}
+
+/// Command-line overrides accepted by `run`.
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct Overrides {
+    /// Control-plane port (filled from the global `--port`)
+    #[arg(skip)]
+    pub port: Option<u16>,
+    /// Chronicle file for events
+    #[arg(long)]
+    pub chronicle: Option<PathBuf>,
+    /// Drop chronicle events below this severity
+    #[arg(long)]
+    pub min_severity: Option<Severity>,
+    /// Quiet period before a changed path is evaluated
+    #[arg(long)]
+    pub debounce_ms: Option<u64>,
+    /// Unregister codebases whose root stays missing this many seconds
+    #[arg(long, value_name = "SECS")]
+    pub forget_missing_after: Option<u64>,
+    /// Log filter (tracing `EnvFilter` directives)
+    #[arg(long)]
+    pub log: Option<String>,
+    /// Delay before the first watcher restart
+    #[arg(long = "restart-initial-ms")]
+    pub restart_initial_delay_ms: Option<u64>,
+    /// Cap for the doubling restart delay
+    #[arg(long = "restart-max-ms")]
+    pub restart_max_delay_ms: Option<u64>,
+    /// Randomise each restart delay by up to this fraction (0..1)
+    #[arg(long = "restart-jitter")]
+    pub restart_jitter: Option<f64>,
+    /// Uptime after which a watcher counts as stable and its delay resets
+    #[arg(long = "restart-reset-after-secs")]
+    pub restart_reset_after_secs: Option<u64>,
+    /// Park a watcher as failed after this many crashes within the window
+    #[arg(long = "restart-max")]
+    pub restart_max_restarts: Option<u32>,
+    /// Window for `--restart-max`
+    #[arg(long = "restart-window-secs")]
+    pub restart_window_secs: Option<u64>,
+}
+
+/// `valve.toml`; every key is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileSettings {
+    port: Option<u16>,
+    chronicle: Option<PathBuf>,
+    min_severity: Option<Severity>,
+    debounce_ms: Option<u64>,
+    forget_missing_after_secs: Option<u64>,
+    log: Option<String>,
+    shutdown_grace_secs: Option<u64>,
+    discovery_interval_secs: Option<u64>,
+    user_config: Option<PathBuf>,
+    restart: RestartFile,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RestartFile {
+    initial_delay_ms: Option<u64>,
+    max_delay_ms: Option<u64>,
+    jitter: Option<f64>,
+    reset_after_secs: Option<u64>,
+    max_restarts: Option<u32>,
+    window_secs: Option<u64>,
+}
+
+/// Effective daemon settings, with where each one came from.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Settings {
+    pub port: u16,
+    pub chronicle: PathBuf,
+    pub min_severity: Severity,
+    /// Default for codebases without a `debounce_ms` override
+    pub debounce_ms: u64,
+    /// Unregister codebases missing for longer than this
+    pub forget_missing_after_secs: Option<u64>,
+    /// `EnvFilter` directives for the daemon's own logs
+    pub log: String,
+    /// How long watchers and control sessions get to drain on shutdown
+    pub shutdown_grace_secs: u64,
+    /// How often watched discovery roots are rescanned
+    pub discovery_interval_secs: u64,
+    /// Outermost persona config layer for every codebase; `None` if off
+    pub user_config: Option<PathBuf>,
+    pub restart: RestartPolicy,
+    /// (key, rendered value, source) in display order
+    #[serde(skip)]
+    pub sources: Vec<(&'static str, String, Source)>,
+}
+
+/// Resolves one key through the precedence layers, remembering its source.
+struct Layers<'a> {
+    file: &'a Path,
+    env: &'a dyn Fn(&str) -> Option<String>,
+    sources: Vec<(&'static str, String, Source)>,
+}
+
+impl Layers<'_> {
+    fn pick<T>(&mut self, key: &'static str, flag: Option<(T, &'static str)>, env: Option<&'static str>, file: Option<T>, default: Option<T>) -> Result<Option<T>>
+    where T: FromStr + Serialize, T::Err: fmt::Display {
+        let env_val = match env.and_then(|var| (self.env)(var).map(|raw| (var, raw))) {
+            Some((var, raw)) => Some((raw.parse::<T>().map_err(|e| anyhow::anyhow!("{}={:?}: {}", var, raw, e))?, Source::Env(var))),
+            None => None,
+        };
+        let picked = flag.map(|(v, f)| (v, Source::Flag(f)))
+            .or(env_val)
+            .or(file.map(|v| (v, Source::File(self.file.to_path_buf()))))
+            .or(default.map(|v| (v, Source::Default)));
+        let rendered = picked.as_ref().map(|(v, _)| serde_json::to_string(v).unwrap_or_default()).unwrap_or_else(|| "(unset)".into());
+        let source = picked.as_ref().map(|(_, s)| s.clone()).unwrap_or(Source::Default);
+        self.sources.push((key, rendered, source));
+        Ok(picked.map(|(v, _)| v))
+    }
+}
+
+impl Settings {
+    /// Layer `o` over the environment over `paths.settings()` over defaults,
+    /// then validate. Named instances default to an ephemeral port.
+    pub fn load(paths: &Paths, named_instance: bool, o: &Overrides) -> Result<Self> {
+        Self::load_with(paths, named_instance, o, &|var| std::env::var(var).ok())
+    }
+
+    pub(crate) fn load_with(paths: &Paths, named_instance: bool, o: &Overrides, env: &dyn Fn(&str) -> Option<String>) -> Result<Self> {
+        let path = paths.settings();
+        let file: FileSettings = match std::fs::read_to_string(&path) {
+            Ok(raw) => toml::from_str(&raw).with_context(|| format!("invalid settings in {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => FileSettings::default(),
+            Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+        };
+        // relative paths in valve.toml are relative to the file
+        let file_chronicle = file.chronicle.map(|c| paths.config.join(c));
+        let mut l = Layers { file: &path, env, sources: vec![] };
+        let r = &file.restart;
+        let d = RestartPolicy::default();
+        let s = Settings {
+            port: l.pick("port", o.port.map(|v| (v, "--port")), Some("SAGE_VALVE_PORT"), file.port, Some(if named_instance { 0 } else { DEFAULT_PORT }))?.unwrap(),
+            chronicle: l.pick("chronicle", o.chronicle.clone().map(|v| (v, "--chronicle")), Some("SAGE_VALVE_CHRONICLE"), file_chronicle, Some(paths.chronicle()))?.unwrap(),
+            min_severity: l.pick("min_severity", o.min_severity.map(|v| (v, "--min-severity")), Some("SAGE_VALVE_MIN_SEVERITY"), file.min_severity, Some(Severity::Info))?.unwrap(),
+            debounce_ms: l.pick("debounce_ms", o.debounce_ms.map(|v| (v, "--debounce-ms")), Some("SAGE_VALVE_DEBOUNCE_MS"), file.debounce_ms, Some(0))?.unwrap(),
+            forget_missing_after_secs: l.pick("forget_missing_after_secs", o.forget_missing_after.map(|v| (v, "--forget-missing-after")), Some("SAGE_VALVE_FORGET_MISSING_AFTER"), file.forget_missing_after_secs, None)?,
+            log: l.pick("log", o.log.clone().map(|v| (v, "--log")), Some("RUST_LOG"), file.log, Some("info".into()))?.unwrap(),
+            shutdown_grace_secs: l.pick("shutdown_grace_secs", None, None, file.shutdown_grace_secs, Some(5))?.unwrap(),
+            discovery_interval_secs: l.pick("discovery_interval_secs", None, None, file.discovery_interval_secs, Some(30))?.unwrap(),
+            // set but empty turns the user layer off
+            user_config: l.pick("user_config", None, Some("SAGE_VALVE_USER_CONFIG"), file.user_config, config::default_user_config())?.filter(|p| !p.as_os_str().is_empty()),
+            restart: RestartPolicy {
+                initial_delay_ms: l.pick("restart.initial_delay_ms", o.restart_initial_delay_ms.map(|v| (v, "--restart-initial-ms")), None, r.initial_delay_ms, Some(d.initial_delay_ms))?.unwrap(),
+                max_delay_ms: l.pick("restart.max_delay_ms", o.restart_max_delay_ms.map(|v| (v, "--restart-max-ms")), None, r.max_delay_ms, Some(d.max_delay_ms))?.unwrap(),
+                jitter: l.pick("restart.jitter", o.restart_jitter.map(|v| (v, "--restart-jitter")), None, r.jitter, Some(d.jitter))?.unwrap(),
+                reset_after_secs: l.pick("restart.reset_after_secs", o.restart_reset_after_secs.map(|v| (v, "--restart-reset-after-secs")), None, r.reset_after_secs, Some(d.reset_after_secs))?.unwrap(),
+                max_restarts: l.pick("restart.max_restarts", o.restart_max_restarts.map(|v| (v, "--restart-max")), None, r.max_restarts, Some(d.max_restarts))?.unwrap(),
+                window_secs: l.pick("restart.window_secs", o.restart_window_secs.map(|v| (v, "--restart-window-secs")), None, r.window_secs, Some(d.window_secs))?.unwrap(),
+            },
+            sources: vec![],
+        };
+        let s = Settings { sources: l.sources, ..s };
+        s.validate()?;
+        Ok(s)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+        let r = &self.restart;
+        if !(0.0..=1.0).contains(&r.jitter) { errors.push(format!("restart.jitter must be between 0 and 1 (got {})", r.jitter)); }
+        if r.initial_delay_ms > r.max_delay_ms { errors.push("restart.initial_delay_ms exceeds restart.max_delay_ms".to_string()); }
+        if r.max_restarts == 0 { errors.push("restart.max_restarts must be at least 1".to_string()); }
+        if r.window_secs == 0 { errors.push("restart.window_secs must be positive".to_string()); }
+        if self.discovery_interval_secs == 0 { errors.push("discovery_interval_secs must be positive".to_string()); }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log) { errors.push(format!("log: {}", e)); }
+        if !errors.is_empty() { bail!("invalid settings:\n  {}", errors.join("\n  ")); }
+        Ok(())
+    }
+
+    /// One `key = value  # source` line per setting, for `config show`.
+    pub fn show(&self) -> String {
+        let width = self.sources.iter().map(|(k, v, _)| k.len() + v.len()).max().unwrap_or(0) + 3;
+        self.sources.iter().map(|(k, v, src)| format!("{:width$}  # {}\n", format!("{} = {}", k, v), src, width = width)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn source<'a>(s: &'a Settings, key: &str) -> &'a Source { &s.sources.iter().find(|(k, _, _)| *k == key).unwrap().2 }
+
+    #[test]
+    fn test_precedence_flag_env_file_default() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let paths = Paths::at(temp_dir.path());
+        std::fs::write(paths.settings(), "port = 6000\nmin_severity = \"high\"\ndebounce_ms = 50\nchronicle = \"logs/c.ndjson\"\n[restart]\nmax_restarts = 9\n").unwrap();
+        let env = |var: &str| (var == "SAGE_VALVE_MIN_SEVERITY").then(|| "critical".to_string());
+        let o = Overrides { debounce_ms: Some(10), ..Default::default() };
+
+        let s = Settings::load_with(&paths, false, &o, &env).expect("valid settings");
+        assert_eq!((s.port, source(&s, "port")), (6000, &Source::File(paths.settings())));
+        assert_eq!((s.min_severity, source(&s, "min_severity")), (Severity::Critical, &Source::Env("SAGE_VALVE_MIN_SEVERITY")));
+        assert_eq!((s.debounce_ms, source(&s, "debounce_ms")), (10, &Source::Flag("--debounce-ms")));
+        assert_eq!(s.chronicle, temp_dir.path().join("logs/c.ndjson"));
+        assert_eq!(s.restart.max_restarts, 9);
+        assert_eq!((s.restart.window_secs, source(&s, "restart.window_secs")), (600, &Source::Default));
+        assert!(s.show().contains("debounce_ms = 10"));
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let paths = Paths::at(temp_dir.path());
+        let no_env = |_: &str| None;
+        std::fs::write(paths.settings(), "prot = 1\n").unwrap();
+        assert!(Settings::load_with(&paths, false, &Overrides::default(), &no_env).is_err());
+        std::fs::write(paths.settings(), "[restart]\njitter = 2.0\nmax_restarts = 0\n").unwrap();
+        let err = Settings::load_with(&paths, false, &Overrides::default(), &no_env).unwrap_err().to_string();
+        assert!(err.contains("restart.jitter") && err.contains("restart.max_restarts"), "{}", err);
+
+        std::fs::remove_file(paths.settings()).unwrap();
+        let bad_env = |var: &str| (var == "SAGE_VALVE_PORT").then(|| "http".to_string());
+        assert!(Settings::load_with(&paths, false, &Overrides::default(), &bad_env).is_err());
+        assert_eq!(Settings::load_with(&paths, true, &Overrides::default(), &no_env).unwrap().port, 0);
+    }
+}
@@ -0,0 +1,91 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+/// Ordered persona severity: `info < low < medium < high < critical < HALT_EVERYTHING`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    #[default]
+    Info,
+    Low,
+    Medium,
+    High,
+    Critical,
+    HaltEverything,
+}
+
+impl Severity {
+    pub const ALL: [Severity; 6] = [Severity::Info, Severity::Low, Severity::Medium, Severity::High, Severity::Critical, Severity::HaltEverything];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+            Severity::Critical => "critical",
+            Severity::HaltEverything => "HALT_EVERYTHING",
+        }
+    }
+}
+
+impl FromStr for Severity {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+            "info" | "informational" | "hint" | "debug" => Ok(Severity::Info),
+            "low" | "minor" => Ok(Severity::Low),
+            "medium" | "moderate" | "warn" | "warning" => Ok(Severity::Medium),
+            "high" | "major" | "error" => Ok(Severity::High),
+            "critical" | "severe" | "blocker" => Ok(Severity::Critical),
+            "halt_everything" | "halt" => Ok(Severity::HaltEverything),
+            _ => Err(format!(
+                "unknown severity '{}' (expected one of: {})",
+                s, Severity::ALL.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result { f.write_str(self.as_str()) }
+}
+
+impl Serialize for Severity {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> { s.serialize_str(self.as_str()) }
+}
+
+impl<'de> Deserialize<'de> for Severity {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(d)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_total_order() {
+        assert!(Severity::Info < Severity::Low);
+        assert!(Severity::High < Severity::Critical);
+        assert!(Severity::Critical < Severity::HaltEverything);
+        assert_eq!(Severity::ALL.iter().max(), Some(&Severity::HaltEverything));
+    }
+
+    #[test]
+    fn test_aliases_and_unknown_values() {
+        assert_eq!("HALT_EVERYTHING".parse::<Severity>().unwrap(), Severity::HaltEverything);
+        assert_eq!("Warning".parse::<Severity>().unwrap(), Severity::Medium);
+        assert_eq!("halt-everything".parse::<Severity>().unwrap(), Severity::HaltEverything);
+        assert!("catastrophic".parse::<Severity>().is_err());
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        let s: Severity = serde_yaml::from_str("error").unwrap();
+        assert_eq!(s, Severity::High);
+        assert_eq!(serde_json::to_string(&Severity::HaltEverything).unwrap(), "\"HALT_EVERYTHING\"");
+        assert!(serde_yaml::from_str::<Severity>("nope").is_err());
+    }
+}
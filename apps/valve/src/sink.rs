@@ -0,0 +1,90 @@
+use crate::{halt::HaltStore, persona::ValveEvent, schedule::RunLog, severity::Severity, state::Codebase};
+use anyhow::Result;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tokio::{io::AsyncWriteExt, sync::broadcast};
+
+/// Live fan-out of valve events to control-plane subscribers.
+pub type EventBus = broadcast::Sender<ValveEvent>;
+
+pub fn bus() -> EventBus { broadcast::channel(1024).0 }
+
+/// Everything a watcher needs to emit events: the chronicle file, its
+/// severity floor, the live bus, the halt state HALT hits trip and the
+/// history of scheduled sweeps.
+#[derive(Clone)]
+pub struct Sinks {
+    pub chronicle: PathBuf,
+    pub min_severity: Severity,
+    pub bus: EventBus,
+    pub halts: HaltStore,
+    pub runs: RunLog,
+}
+
+impl Sinks {
+    pub fn new(chronicle: PathBuf, min_severity: Severity, bus: EventBus, halts: HaltStore, runs: RunLog) -> Self { Self { chronicle, min_severity, bus, halts, runs } }
+
+    /// Apply a codebase's chronicle and severity overrides.
+    pub fn for_codebase(&self, cb: &Codebase) -> Self {
+        let mut s = self.clone();
+        if let Some(c) = &cb.overrides.chronicle { s.chronicle = cb.path.join(c); }
+        if let Some(m) = cb.overrides.min_severity { s.min_severity = m; }
+        s
+    }
+}
+
+/// Append-only NDJSON file with a severity floor.
+pub struct NdjsonSink {
+    file: tokio::fs::File,
+    min_severity: Severity,
+}
+
+impl NdjsonSink {
+    pub fn open(path: &Path, min_severity: Severity) -> Result<Self> {
+        if let Some(dir) = path.parent() { std::fs::create_dir_all(dir)?; }
+        let f = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: tokio::fs::File::from_std(f), min_severity })
+    }
+
+    /// Write `ev` unless it is below the floor; returns whether it was written.
+    pub async fn emit(&mut self, ev: &ValveEvent) -> Result<bool> {
+        if ev.severity < self.min_severity { return Ok(false); }
+        self.record(ev).await?;
+        Ok(true)
+    }
+
+    /// Write any chronicle record, bypassing the severity floor.
+    pub async fn record<T: Serialize>(&mut self, rec: &T) -> Result<()> {
+        let line = serde_json::to_string(rec)? + "\n";
+        self.file.write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<()> { self.file.flush().await?; Ok(()) }
+
+    /// Flush and fsync, so records survive a crash or power loss.
+    pub async fn sync(&mut self) -> Result<()> { self.flush().await?; self.file.sync_all().await?; Ok(()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn event(severity: Severity) -> ValveEvent {
+        ValveEvent { persona: "P".into(), repo: "/r".into(), file: "a".into(), reason: "glob".into(), severity, ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn test_min_severity_filters_lines() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let path = temp_dir.path().join("chronicles/valve.ndjson");
+        let mut sink = NdjsonSink::open(&path, Severity::High).unwrap();
+        assert!(!sink.emit(&event(Severity::Low)).await.unwrap());
+        assert!(sink.emit(&event(Severity::Critical)).await.unwrap());
+        sink.flush().await.unwrap();
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(raw.lines().count(), 1);
+        assert!(raw.contains("\"severity\":\"critical\""));
+    }
+}
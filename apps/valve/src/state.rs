@@ -1,38 +1,398 @@
-use anyhow::{Context, Result};
-use directories::ProjectDirs;
+use anyhow::{bail, Context, Result};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, fs, path::{Path, PathBuf}, sync::Arc};
+use serde_json::{json, Value};
+use crate::{discover::DiscoveryRoot, severity::Severity};
+use chrono::{DateTime, Utc};
+use std::{collections::{BTreeMap, BTreeSet}, fs, io::Write, path::{Path, PathBuf}, sync::Arc};
+use tracing::{info, warn};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Codebase { pub id: String, pub path: PathBuf }
+/// Current on-disk registry format.
+pub const REGISTRY_VERSION: u32 = 3;
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
-pub struct Registry { pub codebases: BTreeMap<String, Codebase> }
+/// Forward migrations; `MIGRATIONS[n]` upgrades a version-`n` document to `n + 1`.
+const MIGRATIONS: &[fn(&mut Value)] = &[
+    // v0 (unversioned) -> v1: only the version stamp is new
+    |v| { if v.get("codebases").is_none() { v["codebases"] = json!({}); } },
+    // v1 -> v2: codebases gain metadata; existing ones stay enabled
+    |v| {
+        if let Some(cbs) = v["codebases"].as_object_mut() {
+            for cb in cbs.values_mut() { if cb.get("enabled").is_none() { cb["enabled"] = json!(true); } }
+        }
+    },
+    // v2 -> v3: discovery roots
+    |v| { if v.get("roots").is_none() { v["roots"] = json!([]); } },
+];
 
-fn reg_path() -> Result<PathBuf> {
-    let d = ProjectDirs::from("dev","sage","valve").context("dirs")?;
-    let p = d.data_dir().join("registry.json");
-    std::fs::create_dir_all(d.data_dir())?; Ok(p)
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryError {
+    #[error("registry version {found} was written by a newer sage-valve (supports up to {supported})")]
+    TooNew { found: u32, supported: u32 },
+    #[error("corrupt registry at {}: {reason}", path.display())]
+    Corrupt { path: PathBuf, reason: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Codebase {
+    pub id: String,
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added_at: Option<DateTime<Utc>>, // unknown for pre-v2 entries
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub overrides: Overrides,
+    /// Discovery root this codebase was registered from, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discovered_by: Option<PathBuf>,
+}
+
+fn enabled_default() -> bool { true }
+
+/// Per-codebase replacements for daemon-wide settings.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Overrides {
+    /// Persona config file (relative paths resolve against the codebase root)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<PathBuf>,
+    /// Chronicle file this codebase's events go to (an absolute path)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chronicle: Option<PathBuf>,
+    /// Severity floor for this codebase's chronicle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_severity: Option<Severity>,
+    /// Quiet period before a changed path is evaluated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debounce_ms: Option<u64>,
+}
+
+impl Codebase {
+    pub fn new(id: String, path: PathBuf) -> Self {
+        Self { id, path, alias: None, tags: BTreeSet::new(), added_at: Some(Utc::now()), enabled: true, overrides: Overrides::default(), discovered_by: None }
+    }
+
+    /// Whether anything was set on this codebase beyond its path.
+    fn has_metadata(&self) -> bool {
+        self.alias.is_some() || !self.tags.is_empty() || !self.enabled || self.overrides != Overrides::default()
+    }
+
+    /// Persona config location, honouring the override.
+    pub fn config_path(&self) -> PathBuf {
+        self.overrides.config.as_ref().map(|c| self.path.join(c)).unwrap_or_else(|| self.path.join(".sage/valve.yml"))
+    }
+}
+
+/// Edits applied by the `Edit` control command; `None`/empty leaves a field alone.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CodebasePatch {
+    #[serde(default)]
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub clear_alias: bool,
+    #[serde(default)]
+    pub add_tags: Vec<String>,
+    #[serde(default)]
+    pub remove_tags: Vec<String>,
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    #[serde(default)]
+    pub overrides: Overrides,
+    #[serde(default)]
+    pub clear_overrides: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Registry {
+    pub version: u32,
+    pub codebases: BTreeMap<String, Codebase>,
+    #[serde(default)]
+    pub roots: Vec<DiscoveryRoot>,
+    /// Backing file; `None` keeps the registry in memory only
+    #[serde(skip)]
+    file: Option<PathBuf>,
+}
+
+impl Default for Registry {
+    fn default() -> Self { Self { version: REGISTRY_VERSION, codebases: BTreeMap::new(), roots: vec![], file: None } }
+}
+
+/// What to do when a new path is inside, or encloses, a registered codebase.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlapPolicy {
+    /// Refuse the registration
+    #[default]
+    Reject,
+    /// Keep a single registration at the outermost root
+    Merge,
+    /// Register both; the inner codebase's config owns its subtree
+    Delegate,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddOutcome {
+    Created,
+    Existing,
+    /// Kept a single root; `absorbed` are the nested codebases it replaced
+    Merged { absorbed: Vec<Codebase> },
+}
+
+/// What one discovery pass changed.
+#[derive(Debug, Default, Serialize)]
+pub struct Discovered {
+    pub registered: Vec<Codebase>,
+    pub unregistered: Vec<Codebase>,
+    /// Repos that could not be registered, with the reason
+    pub skipped: Vec<(PathBuf, String)>,
+}
+
+fn backup_path(p: &Path) -> PathBuf { p.with_extension("json.bak") }
+
+/// Parse a registry document, applying forward migrations.
+fn parse(raw: &str) -> Result<(Registry, bool)> {
+    let mut v: Value = serde_json::from_str(raw)?;
+    let found = v.get("version").and_then(Value::as_u64).unwrap_or(0) as u32;
+    if found > REGISTRY_VERSION { return Err(RegistryError::TooNew { found, supported: REGISTRY_VERSION }.into()); }
+    for (ver, migrate) in MIGRATIONS.iter().enumerate().skip(found as usize) {
+        migrate(&mut v);
+        v["version"] = json!(ver + 1);
+    }
+    Ok((serde_json::from_value(v)?, found < REGISTRY_VERSION))
+}
+
+/// Write `bytes` to a sibling temp file, fsync it and rename it over `path`,
+/// so readers only ever see the old or the new contents.
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let dir = path.parent().context("path has no parent")?;
+    fs::create_dir_all(dir)?;
+    let name = path.file_name().context("path has no file name")?.to_string_lossy();
+    let tmp = dir.join(format!(".{}.tmp", name));
+    let mut f = fs::File::create(&tmp)?;
+    f.write_all(bytes)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)?;
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Hold an exclusive advisory lock on `<path>.lock` while `f` runs. Stores
+/// shared between processes (halts, sweep history) read, modify and write
+/// inside `f`. The registry only takes it to write: the daemon holding the
+/// instance lock is its only writer, so its in-memory copy is current.
+pub fn with_lock<T>(path: &Path, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    if let Some(dir) = path.parent() { fs::create_dir_all(dir)?; }
+    let name = path.file_name().context("path has no file name")?.to_string_lossy();
+    let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(path.with_file_name(format!("{}.lock", name)))?;
+    let mut lock = fd_lock::RwLock::new(file);
+    let _guard = lock.write()?;
+    f()
 }
 
 impl Registry {
-    pub fn load_or_default() -> Result<Self> {
-        let p = reg_path()?;
-        if p.exists() { Ok(serde_json::from_str(&fs::read_to_string(p)?)?) } else { Ok(Self::default()) }
+    /// Load the registry at `p` (empty if absent); later edits persist there.
+    /// A corrupt file is quarantined and the last good backup used in its
+    /// place, so the daemon still starts.
+    pub fn load_or_default(p: &Path) -> Result<Self> {
+        let mut reg = Self::recover_from(p)?;
+        reg.file = Some(p.to_path_buf());
+        Ok(reg)
+    }
+
+    fn load_from(p: &Path) -> Result<Self> {
+        if !p.exists() { return Ok(Self::default()); }
+        let raw = fs::read_to_string(p).with_context(|| format!("cannot read registry at {}", p.display()))?;
+        let (mut reg, migrated) = parse(&raw).map_err(|e| match e.downcast::<RegistryError>() {
+            Ok(e) => e,
+            Err(e) => RegistryError::Corrupt { path: p.to_path_buf(), reason: e.to_string() },
+        })?;
+        if reg.dedupe() || migrated { reg.persist_to(p)?; }
+        Ok(reg)
+    }
+
+    /// [`Self::load_from`], quarantining a file that does not parse. Other
+    /// failures (unreadable, too new, cannot write back) are returned.
+    fn recover_from(p: &Path) -> Result<Self> {
+        let err = match Self::load_from(p) {
+            Ok(reg) => return Ok(reg),
+            Err(e) if matches!(e.downcast_ref(), Some(RegistryError::Corrupt { .. })) => e,
+            Err(e) => return Err(e),
+        };
+        let quarantine = p.with_extension(format!("json.corrupt-{}", chrono::Utc::now().format("%Y%m%dT%H%M%S")));
+        fs::rename(p, &quarantine)?;
+        warn!(?err, quarantined = %quarantine.display(), "registry corrupt");
+        let reg = match fs::read_to_string(backup_path(p)).map_err(anyhow::Error::from).and_then(|raw| parse(&raw)) {
+            Ok((reg, _)) => { info!(codebases = reg.codebases.len(), "restored registry from last good backup"); reg }
+            Err(e) => { warn!(?e, "no usable registry backup; starting empty"); Self::default() }
+        };
+        reg.persist_to(p)?;
+        Ok(reg)
+    }
+
+    /// Drop duplicate registrations of the same path left by older versions,
+    /// keeping the first id. Returns whether anything was removed.
+    fn dedupe(&mut self) -> bool {
+        let mut seen = std::collections::HashSet::new();
+        let before = self.codebases.len();
+        self.codebases.retain(|_, cb| seen.insert(cb.path.clone()));
+        self.codebases.len() != before
+    }
+
+    fn persist(&self) -> Result<()> {
+        match &self.file { Some(p) => self.persist_to(p), None => Ok(()) }
+    }
+
+    /// Atomically replace the registry under an advisory lock, keeping the
+    /// previous (parseable) file as the last good backup.
+    fn persist_to(&self, p: &Path) -> Result<()> {
+        with_lock(p, || {
+            if let Ok(raw) = fs::read_to_string(p) {
+                if parse(&raw).is_ok() { write_atomic(&backup_path(p), raw.as_bytes())?; }
+            }
+            write_atomic(p, &serde_json::to_vec_pretty(self)?)
+        })
     }
-    fn persist(&self) -> Result<()> { fs::write(reg_path()?, serde_json::to_vec_pretty(self)?)?; Ok(()) }
-    pub fn add(&mut self, path: impl AsRef<Path>) -> Result<Codebase> {
+
+    /// Register `path`, idempotently on its canonical form. Overlaps with
+    /// registered codebases are resolved according to `on_overlap`. A merge
+    /// refuses to absorb a nested codebase with an alias, tags, overrides or
+    /// the enabled flag cleared, since none of that applies to the new root.
+    pub fn add(&mut self, path: impl AsRef<Path>, on_overlap: OverlapPolicy) -> Result<(Codebase, AddOutcome)> {
         let p = path.as_ref().canonicalize().context("invalid path")?;
+        if let Some(cb) = self.codebases.values().find(|c| c.path == p) { return Ok((cb.clone(), AddOutcome::Existing)); }
+        let outer = self.codebases.values().find(|c| p.starts_with(&c.path)).cloned();
+        let inner: Vec<Codebase> = self.codebases.values().filter(|c| c.path.starts_with(&p)).cloned().collect();
+        let mut outcome = AddOutcome::Created;
+        if outer.is_some() || !inner.is_empty() {
+            match on_overlap {
+                OverlapPolicy::Reject => {
+                    let other = outer.as_ref().or(inner.first()).unwrap();
+                    bail!("{} overlaps registered codebase {} ({}); use --on-overlap merge or delegate", p.display(), other.id, other.path.display());
+                }
+                OverlapPolicy::Merge => {
+                    if let Some(outer) = outer { return Ok((outer, AddOutcome::Merged { absorbed: vec![] })); }
+                    if let Some(c) = inner.iter().find(|c| c.has_metadata()) {
+                        bail!("{} would absorb codebase {} ({}), dropping its alias, tags, overrides or enabled flag; clear them with `edit` or unregister it first", p.display(), c.id, c.path.display());
+                    }
+                    for c in &inner { self.codebases.remove(&c.id); }
+                    outcome = AddOutcome::Merged { absorbed: inner };
+                }
+                OverlapPolicy::Delegate => {}
+            }
+        }
         let id = Uuid::new_v4().to_string();
-        let cb = Codebase { id: id.clone(), path: p }; self.codebases.insert(id.clone(), cb.clone()); self.persist()?; Ok(cb)
+        let cb = Codebase::new(id.clone(), p); self.codebases.insert(id.clone(), cb.clone()); self.persist()?; Ok((cb, outcome))
+    }
+
+    /// Register the repos `found` under `root`, as returned by
+    /// [`crate::discover::scan`]. A watched root is remembered, and codebases
+    /// discovered from it that are no longer found are unregistered; those
+    /// whose root is gone altogether are left to the supervisor, which waits
+    /// `forget_missing_after` for them to come back. Persists only if
+    /// something changed.
+    pub fn apply_discovery(&mut self, root: DiscoveryRoot, found: &[PathBuf], watch: bool) -> Result<Discovered> {
+        let mut out = Discovered::default();
+        for path in found.iter() {
+            match self.add(path, root.on_overlap) {
+                Ok((cb, outcome)) => {
+                    // a merge into an enclosing codebase registers nothing new
+                    let absorbed = match outcome {
+                        AddOutcome::Created => vec![],
+                        AddOutcome::Merged { absorbed } if !absorbed.is_empty() => absorbed,
+                        _ => continue,
+                    };
+                    let cb = self.codebases.get_mut(&cb.id).unwrap();
+                    cb.discovered_by = Some(root.path.clone());
+                    out.registered.push(cb.clone());
+                    out.unregistered.extend(absorbed);
+                }
+                Err(e) => out.skipped.push((path.clone(), e.to_string())),
+            }
+        }
+        let mut roots_changed = false;
+        if watch {
+            let gone: Vec<String> = self.codebases.values()
+                .filter(|c| c.discovered_by.as_ref() == Some(&root.path) && !found.contains(&c.path) && c.path.is_dir())
+                .map(|c| c.id.clone()).collect();
+            out.unregistered = gone.iter().filter_map(|id| self.codebases.remove(id)).collect();
+            if !self.roots.contains(&root) {
+                self.roots.retain(|r| r.path != root.path);
+                self.roots.push(root);
+                roots_changed = true;
+            }
+        }
+        if !out.registered.is_empty() || !out.unregistered.is_empty() || roots_changed { self.persist()?; }
+        Ok(out)
+    }
+
+    /// Stop watching a discovery root; its codebases stay registered.
+    pub fn remove_root(&mut self, t: &str) -> Result<bool> {
+        let canon = Path::new(t).canonicalize().ok();
+        let before = self.roots.len();
+        self.roots.retain(|r| r.path.to_string_lossy() != t && Some(&r.path) != canon.as_ref());
+        if self.roots.len() == before { return Ok(false); }
+        self.persist()?;
+        Ok(true)
+    }
+
+    /// Roots of other codebases nested inside `cb`; their own config owns them.
+    pub fn nested_roots(&self, cb: &Codebase) -> Vec<PathBuf> {
+        self.codebases.values().filter(|c| c.id != cb.id && c.path.starts_with(&cb.path)).map(|c| c.path.clone()).collect()
+    }
+
+    /// Find a codebase by id, alias, path or unique id prefix.
+    pub fn resolve(&self, t: &str) -> Option<&Codebase> {
+        if let Some(cb) = self.codebases.get(t) { return Some(cb); }
+        if let Some(cb) = self.codebases.values().find(|c| c.alias.as_deref() == Some(t)) { return Some(cb); }
+        let canon = Path::new(t).canonicalize().ok();
+        if let Some(cb) = self.codebases.values().find(|c| c.path.to_string_lossy() == t || Some(&c.path) == canon.as_ref()) { return Some(cb); }
+        let mut prefixed = self.codebases.values().filter(|c| t.len() >= 4 && c.id.starts_with(t));
+        match (prefixed.next(), prefixed.next()) { (Some(cb), None) => Some(cb), _ => None }
+    }
+
+    /// Root of the codebase `t` names in the registry at `p`, for commands
+    /// that run without the daemon; `t` itself, as a path, when nothing
+    /// matches or the registry cannot be read. Never writes the registry.
+    pub fn path_of(p: &Path, t: &Path) -> PathBuf {
+        let reg = fs::read_to_string(p).ok().and_then(|raw| parse(&raw).ok()).map(|(reg, _)| reg).unwrap_or_default();
+        t.to_str().and_then(|t| reg.resolve(t)).map(|cb| cb.path.clone()).unwrap_or_else(|| t.to_path_buf())
     }
+
     pub fn remove_by_id_or_path(&mut self, t: &str) -> Result<Option<Codebase>> {
-        if let Some(cb) = self.codebases.remove(t) { self.persist()?; return Ok(Some(cb)); }
-        let mut found: Option<String> = None;
-        for (id, cb) in &self.codebases { if cb.path.to_string_lossy() == t { found = Some(id.clone()); break; } }
-        if let Some(id) = found { let cb = self.codebases.remove(&id); self.persist()?; Ok(cb) } else { Ok(None) }
+        let Some(id) = self.resolve(t).map(|c| c.id.clone()) else { return Ok(None) };
+        let cb = self.codebases.remove(&id); self.persist()?; Ok(cb)
+    }
+
+    /// Apply `patch` to the codebase `t` resolves to, returning the result.
+    pub fn edit(&mut self, t: &str, patch: CodebasePatch) -> Result<Codebase> {
+        let id = self.resolve(t).map(|c| c.id.clone()).with_context(|| format!("no codebase matches '{}'", t))?;
+        // the daemon has no working directory of the caller's to resolve it against
+        if let Some(c) = patch.overrides.chronicle.as_ref().filter(|c| c.is_relative()) { bail!("chronicle override must be an absolute path, not '{}'", c.display()); }
+        if let Some(alias) = &patch.alias {
+            if alias.is_empty() || alias.contains(std::path::MAIN_SEPARATOR) { bail!("invalid alias '{}'", alias); }
+            if self.codebases.values().any(|c| c.id != id && (c.alias.as_deref() == Some(alias) || c.id == *alias)) { bail!("alias '{}' already in use", alias); }
+        }
+        let cb = self.codebases.get_mut(&id).unwrap();
+        if patch.clear_alias { cb.alias = None; }
+        if let Some(alias) = patch.alias { cb.alias = Some(alias); }
+        for t in patch.remove_tags { cb.tags.remove(&t); }
+        cb.tags.extend(patch.add_tags);
+        if let Some(enabled) = patch.enabled { cb.enabled = enabled; }
+        if patch.clear_overrides { cb.overrides = Overrides::default(); }
+        let o = patch.overrides;
+        if o.config.is_some() { cb.overrides.config = o.config; }
+        if o.chronicle.is_some() { cb.overrides.chronicle = o.chronicle; }
+        if o.min_severity.is_some() { cb.overrides.min_severity = o.min_severity; }
+        if o.debounce_ms.is_some() { cb.overrides.debounce_ms = o.debounce_ms; }
+        let cb = cb.clone();
+        self.persist()?;
+        Ok(cb)
     }
 }
 
@@ -44,24 +404,29 @@ impl SharedRegistry { pub fn new(reg: Registry) -> Self { Self(Arc::new(RwLock::
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::paths::Paths;
     use tempfile::TempDir;
     
     #[test]
     fn test_registry_add_and_remove() {
         let temp_dir = TempDir::new().expect("Failed to create temp directory");
-        std::env::set_var("HOME", temp_dir.path());
+        let paths = Paths::at(temp_dir.path());
         
-        let mut registry = Registry::load_or_default().expect("Failed to load registry");
+        let mut registry = Registry::load_or_default(&paths.registry()).expect("Failed to load registry");
         let initial_count = registry.codebases.len();
         
         // Add a codebase
         let test_path = temp_dir.path().join("test_project");
         std::fs::create_dir_all(&test_path).expect("Failed to create test directory");
-        let codebase = registry.add(&test_path).expect("Failed to add codebase");
+        let (codebase, _) = registry.add(&test_path, OverlapPolicy::Reject).expect("Failed to add codebase");
         
         assert_eq!(registry.codebases.len(), initial_count + 1);
         assert_eq!(codebase.path, test_path.canonicalize().unwrap());
         
+        // The registration is persisted under the explicit state dir
+        let reloaded = Registry::load_or_default(&paths.registry()).expect("Failed to reload registry");
+        assert!(reloaded.codebases.contains_key(&codebase.id));
+        
         // Remove by ID
         let removed = registry.remove_by_id_or_path(&codebase.id).expect("Failed to remove codebase");
         assert!(removed.is_some());
@@ -71,9 +436,9 @@ mod tests {
     #[test]
     fn test_shared_registry() {
         let temp_dir = TempDir::new().expect("Failed to create temp directory");
-        std::env::set_var("HOME", temp_dir.path());
+        let paths = Paths::at(temp_dir.path());
         
-        let registry = Registry::load_or_default().expect("Failed to load registry");
+        let registry = Registry::load_or_default(&paths.registry()).expect("Failed to load registry");
         let shared_registry = SharedRegistry::new(registry);
         
         // Test that we can clone the shared registry
@@ -83,12 +448,191 @@ mod tests {
         assert!(std::ptr::eq(shared_registry.0.as_ref(), shared_registry_clone.0.as_ref()));
     }
     
+    #[test]
+    fn test_add_is_idempotent_on_canonical_path() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let paths = Paths::at(temp_dir.path());
+        
+        let mut registry = Registry::load_or_default(&paths.registry()).expect("Failed to load registry");
+        let test_path = temp_dir.path().join("test_project");
+        std::fs::create_dir_all(&test_path).expect("Failed to create test directory");
+        
+        let (first, _) = registry.add(&test_path, OverlapPolicy::Reject).expect("Failed to add codebase");
+        let (second, outcome) = registry.add(test_path.join("."), OverlapPolicy::Reject).expect("Failed to add codebase");
+        assert_eq!(first.id, second.id);
+        assert_eq!(outcome, AddOutcome::Existing);
+        assert_eq!(registry.codebases.values().filter(|c| c.path == first.path).count(), 1);
+    }
+    
+    #[test]
+    fn test_overlap_policies() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        
+        let mut registry = Registry::default();
+        let outer = temp_dir.path().join("mono");
+        let inner = outer.join("packages/app");
+        std::fs::create_dir_all(&inner).expect("Failed to create test directory");
+        
+        let (outer_cb, _) = registry.add(&outer, OverlapPolicy::Reject).expect("Failed to add codebase");
+        assert!(registry.add(&inner, OverlapPolicy::Reject).is_err());
+        
+        let (merged, outcome) = registry.add(&inner, OverlapPolicy::Merge).expect("merge");
+        assert_eq!((merged.id.as_str(), outcome), (outer_cb.id.as_str(), AddOutcome::Merged { absorbed: vec![] }));
+        assert_eq!(registry.codebases.len(), 1);
+        
+        let (inner_cb, _) = registry.add(&inner, OverlapPolicy::Delegate).expect("delegate");
+        assert_eq!(registry.codebases.len(), 2);
+        assert_eq!(registry.nested_roots(&outer_cb), vec![inner_cb.path.clone()]);
+        assert!(registry.nested_roots(&inner_cb).is_empty());
+        
+        // registering the enclosing root with merge absorbs the nested ones
+        registry.remove_by_id_or_path(&outer_cb.id).expect("remove");
+        // ...unless merging would drop what was set on them
+        registry.edit(&inner_cb.id, CodebasePatch { alias: Some("app".into()), ..Default::default() }).expect("edit");
+        assert!(registry.add(&outer, OverlapPolicy::Merge).is_err());
+        assert_eq!(registry.codebases.len(), 1);
+        registry.edit(&inner_cb.id, CodebasePatch { clear_alias: true, ..Default::default() }).expect("edit");
+        let (root, outcome) = registry.add(&outer, OverlapPolicy::Merge).expect("merge outer");
+        assert_eq!(outcome, AddOutcome::Merged { absorbed: vec![inner_cb.clone()] });
+        assert_eq!(registry.codebases.keys().collect::<Vec<_>>(), vec![&root.id]);
+    }
+    
+    #[test]
+    fn test_migrates_unversioned_registry() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let p = temp_dir.path().join("registry.json");
+        fs::write(&p, r#"{"codebases":{"a":{"id":"a","path":"/tmp/a"}}}"#).unwrap();
+        
+        let reg = Registry::load_from(&p).expect("Failed to load registry");
+        assert_eq!(reg.version, REGISTRY_VERSION);
+        assert_eq!(reg.codebases.len(), 1);
+        assert!(reg.codebases["a"].enabled);
+        let on_disk: Value = serde_json::from_str(&fs::read_to_string(&p).unwrap()).unwrap();
+        assert_eq!(on_disk["version"], REGISTRY_VERSION);
+        
+        fs::write(&p, r#"{"version":999,"codebases":{}}"#).unwrap();
+        let err = Registry::recover_from(&p).expect_err("newer registries are not quarantined");
+        assert!(err.downcast_ref::<RegistryError>().is_some());
+        assert!(p.exists());
+    }
+    
+    #[test]
+    fn test_corrupt_registry_falls_back_to_backup() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let p = temp_dir.path().join("registry.json");
+        let mut reg = Registry::default();
+        reg.codebases.insert("a".into(), Codebase::new("a".into(), "/tmp/a".into()));
+        reg.persist_to(&p).unwrap();
+        reg.codebases.insert("b".into(), Codebase::new("b".into(), "/tmp/b".into()));
+        reg.persist_to(&p).unwrap();
+        
+        // simulate a torn write
+        fs::write(&p, r#"{"version":2,"codebases":{"a":"#).unwrap();
+        assert!(Registry::load_from(&p).is_err());
+        
+        let recovered = Registry::recover_from(&p).expect("daemon should still start");
+        assert_eq!(recovered.codebases.keys().collect::<Vec<_>>(), vec!["a"]);
+        let quarantined = fs::read_dir(temp_dir.path()).unwrap().filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().contains(".corrupt-")).count();
+        assert_eq!(quarantined, 1);
+        assert!(Registry::load_from(&p).is_ok());
+
+        // unreadable is not corrupt: it fails and stays where it is
+        let unreadable = temp_dir.path().join("unreadable.json");
+        fs::create_dir(&unreadable).unwrap();
+        assert!(Registry::recover_from(&unreadable).is_err());
+        assert!(unreadable.is_dir());
+    }
+    
+    #[test]
+    fn test_edit_metadata_and_resolve_by_alias() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        
+        let mut registry = Registry::default();
+        let a = temp_dir.path().join("a");
+        let b = temp_dir.path().join("b");
+        std::fs::create_dir_all(&a).expect("Failed to create test directory");
+        std::fs::create_dir_all(&b).expect("Failed to create test directory");
+        let (cb_a, _) = registry.add(&a, OverlapPolicy::Reject).expect("Failed to add codebase");
+        let (cb_b, _) = registry.add(&b, OverlapPolicy::Reject).expect("Failed to add codebase");
+        assert!(cb_a.added_at.is_some());
+        
+        let patch = CodebasePatch {
+            alias: Some("web".into()),
+            add_tags: vec!["frontend".into()],
+            enabled: Some(false),
+            overrides: Overrides { debounce_ms: Some(250), ..Default::default() },
+            ..Default::default()
+        };
+        let edited = registry.edit(&cb_a.id, patch).expect("Failed to edit codebase");
+        assert_eq!(edited.alias.as_deref(), Some("web"));
+        assert!(edited.tags.contains("frontend"));
+        assert!(!edited.enabled);
+        assert_eq!(edited.overrides.debounce_ms, Some(250));
+        
+        assert_eq!(registry.resolve("web").map(|c| &c.id), Some(&cb_a.id));
+        assert_eq!(registry.resolve(&cb_b.id[..8]).map(|c| &c.id), Some(&cb_b.id));
+        let dup = CodebasePatch { alias: Some("web".into()), ..Default::default() };
+        assert!(registry.edit(&cb_b.id, dup).is_err());
+        let relative = CodebasePatch { overrides: Overrides { chronicle: Some("events.ndjson".into()), ..Default::default() }, ..Default::default() };
+        assert!(registry.edit(&cb_b.id, relative).is_err());
+        assert!(registry.resolve(&cb_b.id).unwrap().overrides.chronicle.is_none());
+        
+        let file = temp_dir.path().join("registry.json");
+        registry.persist_to(&file).expect("Failed to persist registry");
+        assert_eq!(Registry::path_of(&file, Path::new("web")), cb_a.path);
+        assert_eq!(Registry::path_of(&file, Path::new(&cb_b.id)), cb_b.path);
+        assert_eq!(Registry::path_of(&file, Path::new("elsewhere")), Path::new("elsewhere"));
+        assert_eq!(Registry::path_of(&temp_dir.path().join("missing.json"), Path::new("web")), Path::new("web"));
+        
+        assert!(registry.remove_by_id_or_path("web").expect("Failed to remove codebase").is_some());
+        assert!(registry.resolve(&cb_a.id).is_none());
+    }
+    
+    #[test]
+    fn test_watched_discovery_root_tracks_clones() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let paths = Paths::at(temp_dir.path().join("state"));
+        let src = temp_dir.path().join("src");
+        std::fs::create_dir_all(src.join("a/.git")).expect("Failed to create repo");
+        std::fs::create_dir_all(src.join("b/.git")).expect("Failed to create repo");
+        
+        let mut registry = Registry::load_or_default(&paths.registry()).expect("Failed to load registry");
+        let root = DiscoveryRoot { path: src.clone(), depth: 1, require_config: false, on_overlap: OverlapPolicy::Reject };
+        let discover = |registry: &mut Registry| {
+            let (root, found) = crate::discover::scan(root.clone()).expect("scan");
+            registry.apply_discovery(root, &found, true).expect("discover")
+        };
+        let first = discover(&mut registry);
+        assert_eq!(first.registered.len(), 2);
+
+        // an unchanged rescan does not rewrite the registry
+        std::fs::remove_file(paths.registry()).unwrap();
+        assert!(discover(&mut registry).registered.is_empty());
+        assert!(!paths.registry().exists());
+
+        // rescans pick up new clones and drop repos that are no longer
+        // repos; a vanished root is left to the missing-root handling
+        std::fs::create_dir_all(src.join("c/.git")).expect("Failed to create repo");
+        std::fs::remove_dir_all(src.join("a")).expect("Failed to delete repo");
+        std::fs::remove_dir_all(src.join("b/.git")).expect("Failed to delete .git");
+        let rescan = discover(&mut registry);
+        assert_eq!(rescan.registered.iter().map(|c| c.path.file_name().unwrap()).collect::<Vec<_>>(), vec!["c"]);
+        assert_eq!(rescan.unregistered.iter().map(|c| c.path.file_name().unwrap()).collect::<Vec<_>>(), vec!["b"]);
+
+        let reloaded = Registry::load_or_default(&paths.registry()).expect("Failed to reload registry");
+        assert_eq!(reloaded.codebases.len(), 2);
+        assert_eq!(reloaded.roots.len(), 1);
+        assert!(registry.remove_root(&src.to_string_lossy()).expect("remove root"));
+        assert!(registry.roots.is_empty());
+    }
+    
     #[test]
     fn test_remove_nonexistent() {
         let temp_dir = TempDir::new().expect("Failed to create temp directory");
-        std::env::set_var("HOME", temp_dir.path());
+        let paths = Paths::at(temp_dir.path());
         
-        let mut registry = Registry::load_or_default().expect("Failed to load registry");
+        let mut registry = Registry::load_or_default(&paths.registry()).expect("Failed to load registry");
         
         // Try to remove a non-existent codebase
         let removed = registry.remove_by_id_or_path("nonexistent").expect("Failed to remove codebase");
@@ -1,67 +1,357 @@
-use crate::{state::{Registry, Codebase}, watch::watch_codebase};
+use crate::{settings::Settings, severity::Severity, sink::{NdjsonSink, Sinks}, state::{Registry, Codebase}, watch::{self, watch_codebase, Inputs, RootMissing}};
 use anyhow::Result;
-use std::{collections::HashMap, path::PathBuf};
-use tokio::{task::JoinHandle, time::{sleep, Duration}};
+use chrono::{DateTime, Utc};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{collections::{BTreeMap, HashMap, VecDeque}, path::PathBuf, sync::Arc, time::Instant};
+use tokio::{sync::{mpsc, watch as signal}, task::JoinHandle, time::{sleep, timeout, timeout_at, Duration}};
 use tracing::{info, warn};
 
+/// Cooperative cancellation: flips to `true` once a task should wind down.
+pub type StopSignal = signal::Receiver<bool>;
+
+/// Resolve once `stop` is set (or its sender is gone).
+pub async fn stopped(stop: &mut StopSignal) { let _ = stop.wait_for(|s| *s).await; }
+
+/// How crashed watchers are restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RestartPolicy {
+    /// Delay before the first restart
+    pub initial_delay_ms: u64,
+    /// Cap for the doubling restart delay
+    pub max_delay_ms: u64,
+    /// Randomise each delay by up to this fraction (0..1)
+    pub jitter: f64,
+    /// Uptime after which a watcher counts as stable and its delay resets
+    pub reset_after_secs: u64,
+    /// Park the watcher as failed after this many crashes within the window
+    pub max_restarts: u32,
+    /// Window for `restart-max`
+    pub window_secs: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self { initial_delay_ms: 1000, max_delay_ms: 60_000, jitter: 0.2, reset_after_secs: 300, max_restarts: 5, window_secs: 600 }
+    }
+}
+
+/// Crash bookkeeping for one watcher under a [`RestartPolicy`].
+struct Restarts {
+    policy: RestartPolicy,
+    delay: Duration,
+    recent: VecDeque<Instant>,
+}
+
+impl Restarts {
+    fn new(policy: RestartPolicy) -> Self { Self { policy, delay: Duration::from_millis(policy.initial_delay_ms), recent: VecDeque::new() } }
+
+    /// Record a crash after `uptime`; returns the delay before restarting,
+    /// or `None` once the circuit breaker trips.
+    fn on_crash(&mut self, now: Instant, uptime: Duration) -> Option<Duration> {
+        let p = &self.policy;
+        if uptime >= Duration::from_secs(p.reset_after_secs) { self.delay = Duration::from_millis(p.initial_delay_ms); }
+        let window = Duration::from_secs(p.window_secs);
+        while self.recent.front().is_some_and(|t| now.duration_since(*t) > window) { self.recent.pop_front(); }
+        self.recent.push_back(now);
+        if self.recent.len() > p.max_restarts as usize { return None; }
+        let base = self.delay;
+        self.delay = (self.delay * 2).min(Duration::from_millis(p.max_delay_ms));
+        let spread = p.jitter.clamp(0.0, 1.0) * (fastrand::f64() * 2.0 - 1.0);
+        Some(base.mul_f64(1.0 + spread))
+    }
+}
+
+/// What the supervisor is doing with a codebase, as reported by `status`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum WatchStatus {
+    Starting,
+    Watching,
+    /// Root is gone; waiting for it to reappear
+    Missing { since: DateTime<Utc> },
+    /// Crashed; restarting after `delay_ms`
+    Restarting { error: String, delay_ms: u64, restarts: usize },
+    /// Crashed too often; parked until its entry changes or a reload
+    Failed { since: DateTime<Utc>, error: String, restarts: usize },
+    Disabled,
+}
+
+/// Live per-codebase status, shared with the control plane.
+pub type StatusBoard = Arc<RwLock<HashMap<String, WatchStatus>>>;
+
 pub struct Supervisor {
+    sinks: Sinks,
+    tasks: HashMap<String, Task>, // key: codebase id
+    board: StatusBoard,
+    /// Unregister codebases missing for longer than this
+    forget_missing_after: Option<Duration>,
+    restart: RestartPolicy,
+    /// For codebases without a debounce override
+    debounce: Duration,
+    user_config: Option<PathBuf>,
+    expired_tx: mpsc::UnboundedSender<String>,
+    expired_rx: mpsc::UnboundedReceiver<String>,
+}
+
+struct Task {
+    handle: JoinHandle<()>,
+    stop: signal::Sender<bool>,
+    spec: Spec,
+    /// Persona config files as the watcher last loaded them
+    inputs: Inputs,
+}
+
+/// Everything a watcher was started from; any change restarts it. Its
+/// persona config is tracked separately, in [`Inputs`].
+#[derive(PartialEq)]
+struct Spec {
+    codebase: Codebase,     // as registered when spawned
+    excludes: Vec<PathBuf>, // nested codebases owned by their own watcher
     chronicle: PathBuf,
-    tasks: HashMap<String, JoinHandle<()>>, // key: codebase id
+    min_severity: Severity,
+    debounce: Duration,
+    user_config: Option<PathBuf>,
+    forget_missing_after: Option<Duration>,
+    restart: RestartPolicy,
+}
+
+/// What a reconcile pass did to the watchers.
+#[derive(Debug, Default, Serialize)]
+pub struct Reconciled {
+    pub started: Vec<String>,
+    pub stopped: Vec<String>,
+    /// id -> why: "registry", "config", "settings" or "revived"
+    pub restarted: BTreeMap<String, &'static str>,
 }
 
 impl Supervisor {
-    pub fn new(chronicle: PathBuf) -> Self { 
-        Self { 
-            chronicle, 
-            tasks: HashMap::new() 
-        } 
+    pub fn new(sinks: Sinks, board: StatusBoard, settings: &Settings) -> Self { 
+        let (expired_tx, expired_rx) = mpsc::unbounded_channel();
+        let mut sup = Self { 
+            sinks, 
+            tasks: HashMap::new(),
+            board,
+            forget_missing_after: None,
+            restart: settings.restart,
+            debounce: Duration::ZERO,
+            user_config: None,
+            expired_tx,
+            expired_rx,
+        };
+        sup.configure(settings);
+        sup
     }
 
-    pub async fn reconcile(&mut self, reg: std::sync::Arc<Registry>) -> Result<()> {
-        let current: std::collections::HashSet<_> = reg.codebases.keys().cloned().collect();
-        // stop tasks that no longer exist
-        self.tasks.retain(|id, handle| { 
-            if !current.contains(id) { 
-                handle.abort(); 
-                false 
-            } else { 
-                true 
-            } 
-        });
-        // start missing
-        for (id, cb) in reg.codebases.iter() { 
+    /// Use new daemon settings; watchers pick them up on the next reconcile.
+    pub fn configure(&mut self, settings: &Settings) {
+        self.sinks.chronicle = settings.chronicle.clone();
+        self.sinks.min_severity = settings.min_severity;
+        self.forget_missing_after = settings.forget_missing_after_secs.map(Duration::from_secs);
+        self.restart = settings.restart;
+        self.debounce = Duration::from_millis(settings.debounce_ms);
+        self.user_config = settings.user_config.clone();
+    }
+
+    fn spec(&self, reg: &Registry, cb: &Codebase) -> Spec {
+        let sinks = self.sinks.for_codebase(cb);
+        Spec {
+            codebase: cb.clone(),
+            excludes: reg.nested_roots(cb),
+            chronicle: sinks.chronicle,
+            min_severity: sinks.min_severity,
+            debounce: cb.overrides.debounce_ms.map(Duration::from_millis).unwrap_or(self.debounce),
+            user_config: self.user_config.clone(),
+            forget_missing_after: self.forget_missing_after,
+            restart: self.restart,
+        }
+    }
+
+    /// Bring watchers in line with `reg`, restarting only those whose entry,
+    /// nested codebases, persona config or settings changed. With `revive`,
+    /// watchers that have stopped (e.g. parked as failed) start again too.
+    pub async fn reconcile(&mut self, reg: std::sync::Arc<Registry>, revive: bool) -> Result<Reconciled> {
+        let mut out = Reconciled::default();
+        let ids: Vec<String> = self.tasks.keys().cloned().collect();
+        for id in ids {
+            let task = &self.tasks[&id];
+            let why = match reg.codebases.get(&id).filter(|c| c.enabled) {
+                None => None,
+                Some(cb) => {
+                    let want = self.spec(&reg, cb);
+                    let have = &task.spec;
+                    if want.codebase != have.codebase || want.excludes != have.excludes { Some("registry") }
+                    else if task.inputs.stale() { Some("config") }
+                    // the rest of the spec comes from settings
+                    else if want != *have { Some("settings") }
+                    else if revive && task.handle.is_finished() { Some("revived") }
+                    else { continue }
+                }
+            };
+            // let it drain in the background; any replacement starts now
+            let _ = task.stop.send(true);
+            self.tasks.remove(&id);
+            self.board.write().remove(&id);
+            match why { Some(why) => { out.restarted.insert(id, why); } None => out.stopped.push(id) }
+        }
+        for (id, cb) in reg.codebases.iter().filter(|(_, c)| c.enabled) { 
             if !self.tasks.contains_key(id) { 
-                self.spawn_watcher(id.clone(), cb.clone()); 
+                if !out.restarted.contains_key(id) { out.started.push(id.clone()); }
+                let spec = self.spec(&reg, cb);
+                self.spawn_watcher(id.clone(), spec); 
             } 
         }
-        Ok(())
+        Ok(out)
     }
 
-    fn spawn_watcher(&mut self, id: String, cb: Codebase) {
-        let chron = self.chronicle.clone();
+    /// Next codebase that stayed missing past `forget_missing_after`.
+    pub async fn expired(&mut self) -> Option<String> { self.expired_rx.recv().await }
+
+    fn spawn_watcher(&mut self, id: String, spec: Spec) {
+        let sinks = self.sinks.clone();
+        let skip = spec.excludes.clone();
+        let debounce = spec.debounce;
+        let user = spec.user_config.clone();
+        let inputs = Inputs::default();
+        let task_inputs = inputs.clone();
+        let cb = spec.codebase.clone();
+        let board = self.board.clone();
+        let forget = spec.forget_missing_after;
+        let expired = self.expired_tx.clone();
+        let mut restarts = Restarts::new(spec.restart);
+        let (stop_tx, mut stop) = signal::channel(false);
         let id_clone = id.clone(); // Clone the id for use in the async block
+        board.write().insert(id.clone(), WatchStatus::Starting);
         let handle = tokio::spawn(async move {
-            let mut backoff = 1u64;
+            let set = |status: WatchStatus| transition(&board, &sinks, &cb, status);
             loop {
-                match watch_codebase(&cb, &chron).await {
+                if !cb.path.is_dir() {
+                    let since = Utc::now();
+                    set(WatchStatus::Missing { since }).await;
+                    warn!(%id_clone, path=%cb.path.display(), "codebase root missing; waiting for it to reappear");
+                    let wait = async {
+                        let wait = watch::wait_for_root(&cb.path);
+                        match forget { Some(d) => timeout(d, wait).await.ok(), None => Some(wait.await) }
+                    };
+                    let back = tokio::select! { _ = stopped(&mut stop) => return, back = wait => back };
+                    match back {
+                        None => {
+                            warn!(%id_clone, "codebase missing too long; unregistering");
+                            let _ = expired.send(id_clone);
+                            return;
+                        }
+                        Some(Err(e)) => {
+                            warn!(%id_clone, ?e, "cannot watch for codebase root");
+                            tokio::select! { _ = stopped(&mut stop) => return, _ = sleep(Duration::from_millis(restarts.policy.initial_delay_ms)) => continue }
+                        }
+                        Some(Ok(())) => match watch::catch_up(&cb, &sinks, &skip, user.as_deref(), &inputs, since.into()).await {
+                            Ok(n) => info!(%id_clone, files = n, "codebase root reappeared; caught up"),
+                            Err(e) => warn!(%id_clone, ?e, "catch-up failed"),
+                        },
+                    }
+                }
+                set(WatchStatus::Watching).await;
+                let started = Instant::now();
+                match watch_codebase(&cb, &sinks, &skip, user.as_deref(), &inputs, debounce, stop.clone()).await {
                     Ok(_) => { 
                         info!(%id_clone, "watcher finished normally"); 
                         break; 
                     }
-                    Err(e) => {
-                        warn!(%id_clone, ?e, "watcher crashed, restarting");
-                        sleep(Duration::from_secs(backoff.min(60))).await;
-                        backoff = (backoff * 2).min(60);
-                    }
+                    Err(e) if e.is::<RootMissing>() => continue,
+                    Err(e) => match restarts.on_crash(Instant::now(), started.elapsed()) {
+                        Some(delay) => {
+                            warn!(%id_clone, ?e, ?delay, "watcher crashed, restarting");
+                            set(WatchStatus::Restarting { error: format!("{:#}", e), delay_ms: delay.as_millis() as u64, restarts: restarts.recent.len() }).await;
+                            tokio::select! { _ = stopped(&mut stop) => return, _ = sleep(delay) => {} }
+                        }
+                        None => {
+                            warn!(%id_clone, ?e, "watcher keeps crashing; parking it as failed");
+                            set(WatchStatus::Failed { since: Utc::now(), error: format!("{:#}", e), restarts: restarts.recent.len() }).await;
+                            return;
+                        }
+                    },
                 }
             }
         });
-        self.tasks.insert(id, handle);
+        self.tasks.insert(id, Task { handle, stop: stop_tx, spec, inputs: task_inputs });
     }
 
-    pub async fn shutdown(&mut self) { 
-        for (_, h) in self.tasks.drain() { 
-            h.abort(); 
+    /// Ask every watcher to drain and sync its sinks, aborting those still
+    /// running at `deadline`. Returns whether all of them finished in time.
+    pub async fn shutdown(&mut self, deadline: tokio::time::Instant) -> bool { 
+        for t in self.tasks.values() { let _ = t.stop.send(true); }
+        let mut clean = true;
+        for (id, mut t) in self.tasks.drain() { 
+            if timeout_at(deadline, &mut t.handle).await.is_err() {
+                warn!(%id, "watcher did not drain before the deadline; aborting");
+                t.handle.abort();
+                clean = false;
+            }
+            self.board.write().remove(&id);
         } 
+        clean
+    }
+}
+
+/// Publish a watcher's new status and record the transition in its chronicle.
+async fn transition(board: &StatusBoard, sinks: &Sinks, cb: &Codebase, status: WatchStatus) {
+    board.write().insert(cb.id.clone(), status.clone());
+    let mut rec = json!({
+        "type": "WATCHER_STATE",
+        "timestamp": Utc::now().to_rfc3339(),
+        "codebase": cb.id,
+        "repo": cb.path,
+    });
+    if let (Some(rec), Ok(serde_json::Value::Object(fields))) = (rec.as_object_mut(), serde_json::to_value(&status)) { rec.extend(fields); }
+    let sinks = sinks.for_codebase(cb);
+    let written = async {
+        let mut chron = NdjsonSink::open(&sinks.chronicle, sinks.min_severity)?;
+        chron.record(&rec).await?;
+        chron.flush().await
+    };
+    if let Err(e) = written.await { warn!(?e, "could not record watcher state"); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RestartPolicy {
+        RestartPolicy { initial_delay_ms: 100, max_delay_ms: 400, jitter: 0.0, reset_after_secs: 60, max_restarts: 3, window_secs: 600 }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_backoff_doubles_caps_and_resets_after_stable_uptime() {
+        let mut r = Restarts::new(RestartPolicy { max_restarts: 10, ..policy() });
+        let t0 = Instant::now();
+        let short = Duration::from_secs(1);
+        let delays: Vec<_> = (0..4).map(|i| r.on_crash(t0 + short * i, short).unwrap().as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 400]);
+        // a watcher that ran for longer than reset_after starts over
+        assert_eq!(r.on_crash(t0 + short * 5, Duration::from_secs(120)).unwrap().as_millis(), 100);
+    }
+
+    #[test]
+    fn test_breaker_trips_within_window_only() {
+        let mut r = Restarts::new(policy());
+        let t0 = Instant::now();
+        let short = Duration::from_secs(1);
+        for i in 0..3 { assert!(r.on_crash(t0 + short * i, short).is_some()); }
+        assert!(r.on_crash(t0 + short * 3, short).is_none());
+
+        // the same number of crashes spread beyond the window is fine
+        let mut r = Restarts::new(policy());
+        for i in 0..6 { assert!(r.on_crash(t0 + Duration::from_secs(601) * i, short).is_some()); }
+    }
+
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let mut r = Restarts::new(RestartPolicy { jitter: 0.5, max_restarts: 100, max_delay_ms: 100, ..policy() });
+        let t0 = Instant::now();
+        for i in 0..50 {
+            let d = r.on_crash(t0 + Duration::from_secs(i), Duration::ZERO).unwrap().as_millis();
+            assert!((50..=150).contains(&d), "{} out of bounds", d);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Structured `triggers:`. An entry is a regex, `{ pattern, min_count }`, or
+//! an `all:`, `any:` or `none:` group of entries, nested freely; a plain
+//! list is an `any:` group, so `triggers: ["a", "b"]` keeps its meaning.
+//!
+//! ```yaml
+//! triggers:
+//!   all:
+//!     - "useEffect"
+//!     - { pattern: "as any", min_count: 5 }
+//!     - none: ["AbortController"]
+//! ```
+
+use regex::Regex;
+use serde::{de::{self, MapAccess, SeqAccess, Visitor}, Deserialize, Deserializer, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Trigger {
+    Pattern(String),
+    Counted { pattern: String, min_count: usize },
+    List(Vec<Trigger>),
+    All { all: Vec<Trigger> },
+    Any { any: Vec<Trigger> },
+    None { none: Vec<Trigger> },
+}
+
+impl<'de> Deserialize<'de> for Trigger {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct V;
+        impl<'de> Visitor<'de> for V {
+            type Value = Trigger;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a regex, a list of triggers, or a mapping with `pattern` or one of `all`, `any`, `none`")
+            }
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Trigger, E> { Ok(Trigger::Pattern(v.to_string())) }
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Trigger, A::Error> {
+                let mut items = vec![];
+                while let Some(t) = seq.next_element()? { items.push(t); }
+                Ok(Trigger::List(items))
+            }
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Trigger, A::Error> {
+                let (mut pattern, mut min_count, mut group) = (None, None, None);
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "pattern" => pattern = Some(map.next_value::<String>()?),
+                        "min_count" => match map.next_value::<usize>()? {
+                            0 => return Err(de::Error::custom("min_count must be at least 1")),
+                            n => min_count = Some(n),
+                        },
+                        "all" | "any" | "none" if group.is_some() => return Err(de::Error::custom("a group takes exactly one of `all`, `any` or `none`")),
+                        "all" | "any" | "none" => group = Some((key.clone(), map.next_value::<Vec<Trigger>>()?)),
+                        _ => return Err(de::Error::unknown_field(&key, &["pattern", "min_count", "all", "any", "none"])),
+                    }
+                }
+                match (pattern, min_count, group) {
+                    (Some(pattern), Some(min_count), None) => Ok(Trigger::Counted { pattern, min_count }),
+                    (Some(pattern), None, None) => Ok(Trigger::Pattern(pattern)),
+                    (None, None, Some((op, items))) => Ok(match op.as_str() {
+                        "all" => Trigger::All { all: items },
+                        "any" => Trigger::Any { any: items },
+                        _ => Trigger::None { none: items },
+                    }),
+                    (None, Some(_), Some(_)) => Err(de::Error::custom("min_count applies to a single `pattern`, not to a group")),
+                    (Some(_), _, Some(_)) => Err(de::Error::custom("a trigger is either a `pattern` or a group, not both")),
+                    (None, _, None) => Err(de::Error::custom("a trigger mapping needs `pattern` or one of `all`, `any`, `none`")),
+                }
+            }
+        }
+        d.deserialize_any(V)
+    }
+}
+
+impl Trigger {
+    /// Every regex with its config path below `path` (`triggers.all[1].pattern`).
+    pub fn patterns(&self, path: &str) -> Vec<(String, &str)> {
+        match self {
+            Trigger::Pattern(p) => vec![(path.to_string(), p.as_str())],
+            Trigger::Counted { pattern, .. } => vec![(format!("{path}.pattern"), pattern.as_str())],
+            Trigger::List(items) => items.iter().enumerate().flat_map(|(i, t)| t.patterns(&format!("{path}[{i}]"))).collect(),
+            Trigger::All { all: items } | Trigger::Any { any: items } | Trigger::None { none: items } => {
+                let op = self.op();
+                items.iter().enumerate().flat_map(|(i, t)| t.patterns(&format!("{path}.{op}[{i}]"))).collect()
+            }
+        }
+    }
+
+    fn op(&self) -> &'static str {
+        match self { Trigger::All { .. } => "all", Trigger::None { .. } => "none", _ => "any" }
+    }
+}
+
+/// A compiled [`Trigger`].
+#[derive(Debug, Clone)]
+pub enum Rule {
+    Pattern { re: Regex, min_count: usize },
+    All(Vec<Rule>),
+    Any(Vec<Rule>),
+    None(Vec<Rule>),
+}
+
+/// Compile `t` (found at `path`), adding the regexes that can make it hold
+/// (those not under a `none:`) to `positive` and each problem to `errors`.
+pub fn compile(t: &Trigger, path: &str, positive: &mut Vec<Regex>, errors: &mut Vec<(String, String)>) -> Option<Rule> {
+    compile_at(t, path, true, positive, errors)
+}
+
+fn compile_at(t: &Trigger, path: &str, wanted: bool, positive: &mut Vec<Regex>, errors: &mut Vec<(String, String)>) -> Option<Rule> {
+    let (pattern, min_count, path) = match t {
+        Trigger::Pattern(p) => (p, 1, path.to_string()),
+        Trigger::Counted { pattern, min_count } => (pattern, *min_count, format!("{path}.pattern")),
+        Trigger::List(items) | Trigger::All { all: items } | Trigger::Any { any: items } | Trigger::None { none: items } => {
+            let child = |i| if matches!(t, Trigger::List(_)) { format!("{path}[{i}]") } else { format!("{path}.{}[{i}]", t.op()) };
+            if items.is_empty() { errors.push((path.to_string(), format!("empty `{}` group", t.op()))); }
+            let wanted = wanted && !matches!(t, Trigger::None { .. });
+            let rules: Vec<Rule> = items.iter().enumerate().filter_map(|(i, t)| compile_at(t, &child(i), wanted, positive, errors)).collect();
+            return (rules.len() == items.len() && !items.is_empty()).then(|| match t {
+                Trigger::All { .. } => Rule::All(rules),
+                Trigger::None { .. } => Rule::None(rules),
+                _ => Rule::Any(rules),
+            });
+        }
+    };
+    match Regex::new(pattern) {
+        Ok(re) => {
+            if wanted { positive.push(re.clone()); }
+            Some(Rule::Pattern { re, min_count })
+        }
+        // syntax errors render the pattern with a caret; keep the last line
+        Err(e) => { errors.push((path, e.to_string().lines().last().unwrap_or_default().trim_start_matches("error: ").to_string())); None }
+    }
+}
+
+impl Rule {
+    /// The clause that satisfies this rule for `text`, if one does:
+    /// `"as any" ×7`, `all("useEffect", none("AbortController"))`, ...
+    pub fn eval(&self, text: &str) -> Option<String> {
+        match self {
+            Rule::Pattern { re, min_count: 1 } => re.is_match(text).then(|| format!("{:?}", re.as_str())),
+            Rule::Pattern { re, min_count } => {
+                let n = re.find_iter(text).count();
+                (n >= *min_count).then(|| format!("{:?} ×{}", re.as_str(), n))
+            }
+            Rule::Any(rules) => rules.iter().find_map(|r| r.eval(text)),
+            Rule::All(rules) => rules.iter().map(|r| r.eval(text)).collect::<Option<Vec<_>>>().map(|v| format!("all({})", v.join(", "))),
+            Rule::None(rules) => rules.iter().all(|r| r.eval(text).is_none()).then(|| self.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (op, rules) = match self {
+            Rule::Pattern { re, min_count: 1 } => return write!(f, "{:?}", re.as_str()),
+            Rule::Pattern { re, min_count } => return write!(f, "{:?} ×≥{}", re.as_str(), min_count),
+            Rule::All(rules) => ("all", rules),
+            Rule::Any(rules) => ("any", rules),
+            Rule::None(rules) => ("none", rules),
+        };
+        write!(f, "{}({})", op, rules.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(yaml: &str) -> Result<Rule, Vec<(String, String)>> {
+        let t: Trigger = serde_yaml::from_str(yaml).map_err(|e| vec![(String::new(), e.to_string())])?;
+        let (mut positive, mut errors) = (vec![], vec![]);
+        compile(&t, "triggers", &mut positive, &mut errors).filter(|_| errors.is_empty()).ok_or(errors)
+    }
+
+    #[test]
+    fn test_groups_nest_and_report_the_clause() {
+        let r = rule(r#"
+all:
+  - "useEffect"
+  - any: ["fetch\\(", "axios"]
+  - none: ["AbortController"]
+"#).unwrap();
+        assert_eq!(r.eval("useEffect(() => { axios.get(u) })").as_deref(), Some(r#"all("useEffect", "axios", none("AbortController"))"#));
+        assert_eq!(r.eval("useEffect(() => { fetch(u, { signal: new AbortController().signal }) })"), None);
+        assert_eq!(r.eval("fetch(u)"), None);
+
+        // a plain list is any
+        assert_eq!(rule(r#"["a", "b"]"#).unwrap().eval("xbx").as_deref(), Some(r#""b""#));
+    }
+
+    #[test]
+    fn test_min_count_thresholds() {
+        let r = rule(r#"{ pattern: "as any", min_count: 3 }"#).unwrap();
+        assert_eq!(r.eval("a as any; b as any;"), None);
+        assert_eq!(r.eval("a as any; b as any; c as any;").as_deref(), Some(r#""as any" ×3"#));
+        assert_eq!(r.to_string(), r#""as any" ×≥3"#);
+    }
+
+    #[test]
+    fn test_malformed_triggers_are_specific() {
+        let err = |yaml| rule(yaml).unwrap_err().into_iter().map(|(p, m)| format!("{p}: {m}")).collect::<Vec<_>>().join("; ");
+        assert!(err(r#"{ all: ["a"], any: ["b"] }"#).contains("exactly one of"));
+        assert!(err(r#"{ pattern: "a", min_count: 0 }"#).contains("at least 1"));
+        assert!(err(r#"{ any: ["a"], min_count: 2 }"#).contains("single `pattern`"));
+        assert!(err(r#"{ patern: "a" }"#).contains("unknown field `patern`"));
+        assert_eq!(err(r#"{ all: ["ok", { none: [] }, { pattern: "(", min_count: 2 }] }"#),
+            "triggers.all[1]: empty `none` group; triggers.all[2].pattern: unclosed group");
+    }
+}
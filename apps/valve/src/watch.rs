@@ -1,56 +1,585 @@
-use crate::{config, persona::{self, ValveEvent}};
+use crate::{condition::Fact, config::{self, CompiledPersona}, git, halt, persona::{self, Change, Facts, ValveEvent}, severity::Severity, sink::{NdjsonSink, Sinks}, state::Codebase, supervisor::{stopped, StopSignal}};
 use anyhow::Result;
-use notify::{RecommendedWatcher, RecursiveMode, Watcher, EventKind};
-use std::{fs, path::Path};
+use chrono::{DateTime, Local};
+use notify::{event::{ModifyKind, RenameMode}, RecommendedWatcher, RecursiveMode, Watcher, EventKind};
+use parking_lot::RwLock;
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::Arc, time::SystemTime};
+use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
 use tracing::{debug, info, warn};
-use tokio::io::AsyncWriteExt;
 
-pub async fn watch_codebase(cb: &crate::state::Codebase, chronicle: &Path) -> Result<()> {
+/// The codebase root disappeared (deleted, moved or unmounted).
+#[derive(Debug, thiserror::Error)]
+#[error("codebase root {} is missing", .0.display())]
+pub struct RootMissing(pub PathBuf);
+
+/// The files a watcher's personas were read from, with their contents (see
+/// [`config::inputs`]). Shared with the supervisor, which restarts the
+/// watcher when one changes; packs and the user config can live outside the
+/// watched tree.
+#[derive(Debug, Clone, Default)]
+pub struct Inputs(Arc<RwLock<Vec<config::Input>>>);
+
+impl Inputs {
+    /// Whether any input differs from what was loaded.
+    pub fn stale(&self) -> bool {
+        self.0.read().iter().any(|(path, raw)| std::fs::read_to_string(path).ok() != *raw)
+    }
+
+    fn contains(&self, path: &Path) -> bool { self.0.read().iter().any(|(p, _)| p == path) }
+}
+
+/// Resolve and compile `cb`'s layers off the runtime (finding nested layers
+/// walks the tree), publishing what they were read from to `inputs` even
+/// when they fail to compile.
+async fn load_personas(cb: &Codebase, user: Option<&Path>, inputs: &Inputs) -> Result<Vec<CompiledPersona>> {
+    let (repo, repo_config, user) = (cb.path.clone(), cb.config_path(), user.map(Path::to_path_buf));
+    let (compiled, files) = tokio::task::spawn_blocking(move || {
+        let layers = config::layers(&repo, &repo_config, user.as_deref());
+        let files = config::inputs(&repo_config, &layers, user.as_deref());
+        ((!layers.is_empty()).then(|| config::compile_layers(&layers, user.as_deref())), files)
+    }).await?;
+    *inputs.0.write() = files;
+    match compiled {
+        None => {
+            warn!(path = %cb.config_path().display(), "no valve.yml (`sage-valve init` writes one); watching anyway");
+            Ok(vec![])
+        }
+        // an invalid config fails the watcher, so `status` shows why
+        Some(compiled) => compiled,
+    }
+}
+
+/// Next sweep for each scheduled persona (by index); a run missed while we
+/// were down is due now.
+fn plan_sweeps(personas: &[CompiledPersona], repo: &Path, sinks: &Sinks) -> Result<Vec<(usize, Option<DateTime<Local>>)>> {
+    let now = Local::now();
+    let mut sweeps = vec![];
+    for (i, p) in personas.iter().enumerate() {
+        let Some(schedule) = &p.schedule else { continue };
+        let last = match sinks.runs.last(repo, &p.name)? {
+            Some(t) => t,
+            None => { sinks.runs.record(repo, &p.name, now)?; now }
+        };
+        sweeps.push((i, schedule.due(last, now)));
+    }
+    Ok(sweeps)
+}
+
+/// Watch `cb` with personas from its layers under `user`, skipping
+/// `excludes` (nested codebases with their own config) and evaluating a
+/// path once it has been quiet for `debounce`; scheduled personas sweep the
+/// tree when due instead. Personas are reloaded when a config file in the
+/// tree changes, and what they were read from is kept in `inputs`. Fails
+/// with [`RootMissing`] once the root goes away. When `stop` is set, stops
+/// watching and evaluates everything already queued. The chronicle is
+/// flushed after each batch and synced however the watch ends.
+pub async fn watch_codebase(cb: &Codebase, sinks: &Sinks, excludes: &[PathBuf], user: Option<&Path>, inputs: &Inputs, debounce: Duration, mut stop: StopSignal) -> Result<()> {
     let repo = cb.path.clone();
-    let cfg = match config::ValveConfig::load_from_repo(&repo) { 
-        Ok(c) => c, 
-        Err(e) => { 
-            warn!(?e, "no valve.yml; watching anyway"); 
-            config::ValveConfig { personas: Default::default() } 
-        } 
-    };
-    let personas = config::compile(&cfg)?;
+    if !repo.is_dir() { return Err(RootMissing(repo).into()); }
+    let mut personas = load_personas(cb, user, inputs).await?;
+    let sinks = sinks.for_codebase(cb);
+    // a chronicle inside the tree would otherwise see its own appends
+    let excludes = &[excludes, std::slice::from_ref(&sinks.chronicle)].concat();
 
     // channel bridge
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
-    let mut watcher: RecommendedWatcher = Watcher::new(move |res| { 
-        let _ = tx.send(res); 
+    let mut watcher: RecommendedWatcher = Watcher::new(move |res| {
+        let _ = tx.send(res);
     }, notify::Config::default())?;
     watcher.watch(&repo, RecursiveMode::Recursive)?;
 
-    info!(repo=%repo.display(), personas = personas.len(), "watching");
+    info!(repo=%repo.display(), personas = personas.len(), ?debounce, "watching");
 
     // writer for chronicles
-    let chron = fs::OpenOptions::new().create(true).append(true).open(chronicle)?;
-    let mut chron = tokio::fs::File::from_std(chron);
-
-    while let Some(res) = rx.recv().await {
-        match res {
-            Ok(event) => {
-                if !matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_) | EventKind::Remove(_)) { 
-                    continue; 
-                }
-                for path in event.paths {
-                    if let Some(rel) = path.strip_prefix(&repo).ok() {
-                        // read content for triggers if file exists
-                        let text = tokio::fs::read_to_string(&path).await.ok();
-                        let hits: Vec<ValveEvent> = persona::match_personas(&personas, &repo, rel, text.as_deref());
-                        for ev in hits {
-                            let line = serde_json::to_string(&ev)? + "\n";
-                            chron.write_all(line.as_bytes()).await?;
-                            debug!(?ev, "valve event");
-                        }
+    let mut chron = NdjsonSink::open(&sinks.chronicle, sinks.min_severity)?;
+
+    let watched: Result<()> = async {
+        let mut pending: HashMap<PathBuf, Pending> = HashMap::new();
+        let queue = |pending: &mut HashMap<PathBuf, Pending>, event: notify::Event| queue(pending, event, excludes, debounce);
+        let mut sweeps = plan_sweeps(&personas, &repo, &sinks)?;
+        let mut checkout = Checkout::read(&repo).await;
+        let mut draining = false;
+        while !draining {
+            let next = pending.values().map(|p| p.due)
+                .chain(sweeps.iter().filter_map(|(_, t)| *t).map(|t| Instant::now() + (t - Local::now()).to_std().unwrap_or_default()))
+                .min();
+            tokio::select! {
+                _ = stopped(&mut stop) => draining = true,
+                res = rx.recv() => match res {
+                    None => break,
+                    Some(Ok(event)) => {
+                        if !repo.is_dir() { return Err(RootMissing(repo.clone()).into()); }
+                        queue(&mut pending, event);
                     }
+                    Some(Err(e)) => warn!(?e, "watch error"),
+                },
+                _ = async { sleep_until(next.unwrap()).await }, if next.is_some() => {}
+            }
+            if draining {
+                // stop accepting fs events, then take what was already reported
+                let _ = watcher.unwatch(&repo);
+                while let Ok(res) = rx.try_recv() { if let Ok(event) = res { queue(&mut pending, event); } }
+            }
+            let now = Instant::now();
+            let due: Vec<PathBuf> = pending.iter().filter(|(_, p)| draining || p.due <= now).map(|(p, _)| p.clone()).collect();
+            // a layer or pack changed, or a nested layer appeared
+            if !draining && due.iter().any(|p| p.ends_with(".sage/valve.yml") || inputs.contains(p)) {
+                match load_personas(cb, user, inputs).await {
+                    Ok(loaded) => {
+                        personas = loaded;
+                        sweeps = plan_sweeps(&personas, &repo, &sinks)?;
+                        info!(repo=%repo.display(), personas = personas.len(), "persona config reloaded");
+                    }
+                    Err(e) => warn!(repo=%repo.display(), ?e, "persona config no longer loads; keeping the previous personas"),
                 }
             }
-            Err(e) => warn!(?e, "watch error"),
+            if due.iter().any(|p| *p == repo.join(".git/HEAD")) { checkout = Checkout::read(&repo).await; }
+            let mut wrote = !due.is_empty();
+            for path in due {
+                let Some(p) = pending.remove(&path) else { continue };
+                // appeared and went again before anyone could see it
+                if p.change == Change::Delete && p.fresh { continue; }
+                evaluate(&path, &checkout, p.change, p.from.as_deref(), &personas, &mut chron, &sinks).await?;
+            }
+            let now = Local::now();
+            for (i, at) in sweeps.iter_mut().filter(|(_, t)| !draining && t.is_some_and(|t| t <= now)) {
+                let p = &personas[*i];
+                let (sp, root, skip, branch) = (p.clone(), repo.clone(), excludes.to_vec(), checkout.branch.clone());
+                let mut walk = tokio::task::spawn_blocking(move || sweep(&sp, &root, &skip, branch));
+                // keep taking events while the tree is walked, and give up on stop
+                let matched = loop {
+                    tokio::select! {
+                        _ = stopped(&mut stop) => break None,
+                        Some(res) = rx.recv() => if let Ok(event) = res { queue(&mut pending, event); },
+                        m = &mut walk => break Some(m?),
+                    }
+                };
+                // stopping; the run is left unrecorded so it is caught up next start
+                let Some(matched) = matched else { break };
+                info!(repo=%repo.display(), persona=%p.name, matched = matched.len(), "sweep");
+                if !matched.is_empty() { emit(persona::sweep_event(p, &repo, matched), &personas, &mut chron, &sinks).await?; wrote = true; }
+                sinks.runs.record(&repo, &p.name, now)?;
+                *at = p.schedule.as_ref().and_then(|s| s.next_after(now));
+            }
+            // so readers following the chronicle see each batch as it lands
+            if wrote { chron.flush().await?; }
+        }
+        Ok(())
+    }.await;
+    // however the watch ended (stop, a vanished root or an error), keep what was written
+    let synced = chron.sync().await;
+    watched.and(synced)
+}
+
+/// A path waiting out its debounce, and what happened to it meanwhile.
+#[derive(Debug, PartialEq)]
+struct Pending {
+    due: Instant,
+    change: Change,
+    /// Old path of a rename
+    from: Option<PathBuf>,
+    /// The path did not exist when the window opened
+    fresh: bool,
+}
+
+/// Fold one fs event into `pending`. Renames are paired when the backend
+/// reports both ends together (inotify does; elsewhere they surface as a
+/// delete and a create), and a file written elsewhere then moved into place
+/// counts as a modify of its destination.
+fn queue(pending: &mut HashMap<PathBuf, Pending>, event: notify::Event, excludes: &[PathBuf], debounce: Duration) {
+    let due = Instant::now() + debounce;
+    let watched = |p: &Path| !excludes.iter().any(|x| p.starts_with(x));
+    if let (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) = (event.kind, &event.paths[..]) {
+        if watched(from) && watched(to) {
+            let (change, origin) = match pending.remove(from) {
+                Some(p) if p.fresh => (Change::Modify, None),
+                Some(Pending { change: Change::Rename, from: Some(origin), .. }) => (Change::Rename, Some(origin)),
+                _ => (Change::Rename, Some(from.clone())),
+            };
+            pending.insert(to.clone(), Pending { due, change, from: origin, fresh: false });
+            return;
+        }
+    }
+    for path in event.paths {
+        let change = match event.kind {
+            EventKind::Create(_) => Change::Create,
+            EventKind::Remove(_) => Change::Delete,
+            // one end of a rename
+            EventKind::Modify(ModifyKind::Name(_)) => if path.exists() { Change::Create } else { Change::Delete },
+            EventKind::Modify(_) => Change::Modify,
+            _ => return,
+        };
+        if !watched(&path) { continue; }
+        let (path, next) = match (pending.remove(&path), change) {
+            (Some(p), Change::Modify) if p.change != Change::Delete => (path, Pending { due, ..p }),
+            // renamed, then deleted: the original is what went away
+            (Some(Pending { change: Change::Rename, from: Some(origin), .. }), Change::Delete) => (origin, Pending { due, change, from: None, fresh: false }),
+            (Some(p), Change::Delete) => (path, Pending { due, change, from: None, fresh: p.fresh }),
+            (Some(p), Change::Create) if p.change == Change::Delete => (path, Pending { due, change: if p.fresh { Change::Create } else { Change::Modify }, from: None, fresh: p.fresh }),
+            _ => (path, Pending { due, change, from: None, fresh: change == Change::Create }),
+        };
+        pending.insert(path, next);
+    }
+}
+
+/// Resolve once `root` exists again, watching its nearest existing ancestor
+/// (and polling, since unmounts are not always reported).
+pub async fn wait_for_root(root: &Path) -> Result<()> {
+    while !root.is_dir() {
+        let ancestor = root.ancestors().skip(1).find(|a| a.is_dir()).unwrap_or(Path::new("/"));
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut watcher: RecommendedWatcher = Watcher::new(move |res| { let _ = tx.send(res); }, notify::Config::default())?;
+        watcher.watch(ancestor, RecursiveMode::NonRecursive)?;
+        let _ = timeout(Duration::from_secs(5), rx.recv()).await;
+        // let a clone or move finish before the watcher starts
+        if root.is_dir() { sleep(Duration::from_millis(250)).await; }
+    }
+    Ok(())
+}
+
+/// Evaluate files changed since `since` (e.g. while the root was missing),
+/// honouring ignore files and skipping `excludes`. Returns files evaluated.
+pub async fn catch_up(cb: &Codebase, sinks: &Sinks, excludes: &[PathBuf], user: Option<&Path>, inputs: &Inputs, since: SystemTime) -> Result<usize> {
+    let personas = load_personas(cb, user, inputs).await?;
+    let sinks = sinks.for_codebase(cb);
+    let excludes = &[excludes, std::slice::from_ref(&sinks.chronicle)].concat();
+    let mut chron = NdjsonSink::open(&sinks.chronicle, sinks.min_severity)?;
+    let changed: Vec<(PathBuf, Change)> = files(&cb.path, excludes)
+        .filter_map(|e| {
+            let meta = e.metadata().ok()?;
+            if meta.modified().ok()? < since { return None; }
+            let change = if meta.created().is_ok_and(|t| t >= since) { Change::Create } else { Change::Modify };
+            Some((e.into_path(), change))
+        }).collect();
+    let checkout = Checkout::read(&cb.path).await;
+    let evaluated: Result<()> = async {
+        for (path, change) in &changed { evaluate(path, &checkout, *change, None, &personas, &mut chron, &sinks).await?; }
+        Ok(())
+    }.await;
+    let flushed = chron.flush().await;
+    evaluated.and(flushed)?;
+    Ok(changed.len())
+}
+
+/// Files under `root` that ignore files don't exclude, skipping `excludes`.
+fn files<'a>(root: &Path, excludes: &'a [PathBuf]) -> impl Iterator<Item = ignore::DirEntry> + 'a {
+    ignore::WalkBuilder::new(root).hidden(false)
+        .filter_entry(|e| e.file_name() != ".git")
+        .build().flatten()
+        .filter(|e| e.file_type().is_some_and(|t| t.is_file()))
+        .filter(move |e| !excludes.iter().any(|x| e.path().starts_with(x)))
+}
+
+/// A watched tree and the branch checked out in it, read once and again
+/// when `.git/HEAD` changes rather than for every change evaluated.
+struct Checkout {
+    root: PathBuf,
+    branch: Option<String>,
+}
+
+impl Checkout {
+    async fn read(root: &Path) -> Self {
+        let r = root.to_path_buf();
+        let branch = tokio::task::spawn_blocking(move || persona::branch(&r)).await.ok().flatten();
+        Self { root: root.to_path_buf(), branch }
+    }
+}
+
+async fn evaluate(path: &Path, checkout: &Checkout, change: Change, from: Option<&Path>, personas: &[CompiledPersona], chron: &mut NdjsonSink, sinks: &Sinks) -> Result<()> {
+    let repo = &checkout.root;
+    let Ok(rel) = path.strip_prefix(repo) else { return Ok(()) };
+    let text = match change {
+        // gone from disk; triggers see the last committed version, if any
+        Change::Delete => {
+            let (repo, blob) = (repo.to_path_buf(), format!("HEAD:{}", rel.display()));
+            tokio::task::spawn_blocking(move || git::git(&repo, &["show", &blob]).ok().and_then(git::text)).await?
         }
+        _ => tokio::fs::read_to_string(path).await.ok(),
+    };
+    let mut facts = Facts::new(repo, rel, text.as_deref()).with_change(change).on_branch(checkout.branch.clone());
+    // ask git only if some condition needs it, and off the runtime
+    if personas.iter().any(|p| p.conditions.iter().any(|c| c.uses(Fact::Tracked))) {
+        let (r, f) = (repo.clone(), rel.to_path_buf());
+        facts = facts.with_tracked(tokio::task::spawn_blocking(move || persona::tracked(&r, &f)).await?);
     }
+    let facts = match from.and_then(|f| f.strip_prefix(repo).ok()) { Some(from) => facts.renamed_from(from), None => facts };
+    for ev in persona::match_facts(personas, &facts) { emit(ev, personas, chron, sinks).await?; }
+    Ok(())
+}
 
+/// Files under `repo` that scheduled persona `p` matches, sorted. Walks
+/// and reads the whole tree, so it belongs on the blocking pool.
+fn sweep(p: &CompiledPersona, repo: &Path, excludes: &[PathBuf], branch: Option<String>) -> Vec<String> {
+    let tracked = p.conditions.iter().any(|c| c.uses(Fact::Tracked));
+    let mut matched = vec![];
+    for e in files(repo, excludes) {
+        let Ok(rel) = e.path().strip_prefix(repo) else { continue };
+        if !p.applies_to(rel) || (!p.globset.is_empty() && !p.globset.is_match(rel)) { continue; }
+        let text = std::fs::read_to_string(e.path()).ok();
+        let mut facts = Facts::new(repo, rel, text.as_deref()).on_branch(branch.clone());
+        if tracked { facts = facts.with_tracked(persona::tracked(repo, rel)); }
+        if persona::matches(p, &facts).is_some() { matched.push(rel.display().to_string()); }
+    }
+    matched.sort();
+    matched
+}
+
+async fn emit(ev: ValveEvent, personas: &[CompiledPersona], chron: &mut NdjsonSink, sinks: &Sinks) -> Result<()> {
+    chron.emit(&ev).await?;
+    if ev.severity == Severity::HaltEverything {
+        if let Some(h) = sinks.halts.trip(&ev)? {
+            let response = personas.iter().find(|p| p.name == ev.persona).and_then(|p| p.response.as_deref());
+            chron.record(&halt::halt_and_report(&h, response)).await?;
+            warn!(repo=%h.repo, persona=%h.persona, file=%h.file, "HALT_EVERYTHING: codebase halted");
+        }
+    }
+    let _ = sinks.bus.send(ev.clone()); // no subscribers is fine
+    debug!(?ev, "valve event");
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{halt::HaltStore, schedule::RunLog, sink};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_catch_up_evaluates_files_changed_since() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let repo = temp_dir.path().join("repo");
+        let sage_dir = repo.join(".sage");
+        fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::write(sage_dir.join("valve.yml"), r#"
+personas:
+  TestWatcher:
+    filters: ["**/*.txt"]
+"#).expect("Failed to write config file");
+        fs::write(repo.join("old.txt"), "x").unwrap();
+        let since = SystemTime::now() - Duration::from_secs(60);
+        fs::File::options().write(true).open(repo.join("old.txt")).unwrap().set_modified(since - Duration::from_secs(60)).unwrap();
+        fs::write(repo.join("new.txt"), "x").unwrap();
+
+        let chronicle = temp_dir.path().join("valve.ndjson");
+        let sinks = Sinks::new(chronicle.clone(), Severity::Info, sink::bus(), HaltStore::at(temp_dir.path().join("halts.json")), RunLog::at(temp_dir.path().join("schedules.json")));
+        let cb = Codebase::new("id".into(), repo.canonicalize().unwrap());
+        assert_eq!(catch_up(&cb, &sinks, &[], None, &Inputs::default(), since).await.unwrap(), 2); // new.txt and valve.yml
+        let raw = fs::read_to_string(&chronicle).unwrap();
+        assert_eq!(raw.lines().count(), 1);
+        assert!(raw.contains("new.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_chronicle_inside_the_tree_is_not_watched() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let repo = temp_dir.path().join("repo");
+        let sage_dir = repo.join(".sage");
+        fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::write(sage_dir.join("valve.yml"), "personas:\n  Everything:\n    filters: [\"**\"]\n").expect("Failed to write config file");
+        let sinks = Sinks::new(temp_dir.path().join("valve.ndjson"), Severity::Info, sink::bus(), HaltStore::at(temp_dir.path().join("halts.json")), RunLog::at(temp_dir.path().join("schedules.json")));
+        let mut cb = Codebase::new("id".into(), repo.canonicalize().unwrap());
+        let chronicle = cb.path.join("events.ndjson");
+        cb.overrides.chronicle = Some(chronicle.clone());
+
+        let (stop_tx, stop) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn(async move { watch_codebase(&cb, &sinks, &[], None, &Inputs::default(), Duration::from_millis(50), stop).await });
+        sleep(Duration::from_millis(300)).await;
+        fs::write(repo.join("a.txt"), "x").unwrap();
+        sleep(Duration::from_millis(1000)).await;
+        stop_tx.send(true).unwrap();
+        timeout(Duration::from_secs(5), task).await.expect("stops").unwrap().unwrap();
+        let raw = fs::read_to_string(&chronicle).unwrap();
+        assert_eq!(raw.lines().count(), 1, "{raw}");
+    }
+
+    #[tokio::test]
+    async fn test_chronicle_is_flushed_while_watching_and_kept_when_the_root_goes() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let repo = temp_dir.path().join("repo");
+        let sage_dir = repo.join(".sage");
+        fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::write(sage_dir.join("valve.yml"), "personas:\n  TestWatcher:\n    filters: [\"**/*.txt\"]\n").expect("Failed to write config file");
+        let chronicle = temp_dir.path().join("valve.ndjson");
+        let sinks = Sinks::new(chronicle.clone(), Severity::Info, sink::bus(), HaltStore::at(temp_dir.path().join("halts.json")), RunLog::at(temp_dir.path().join("schedules.json")));
+        let cb = Codebase::new("id".into(), repo.canonicalize().unwrap());
+
+        let (_stop_tx, stop) = tokio::sync::watch::channel(false);
+        let task = tokio::spawn(async move { watch_codebase(&cb, &sinks, &[], None, &Inputs::default(), Duration::from_millis(50), stop).await });
+        sleep(Duration::from_millis(300)).await;
+        fs::write(repo.join("a.txt"), "x").unwrap();
+        sleep(Duration::from_millis(500)).await;
+        // visible to a reader while the watcher is still running
+        assert!(fs::read_to_string(&chronicle).unwrap().contains("a.txt"));
+
+        fs::write(repo.join("b.txt"), "x").unwrap();
+        fs::remove_dir_all(&repo).unwrap();
+        let err = timeout(Duration::from_secs(5), task).await.expect("ends").unwrap().expect_err("root missing");
+        assert!(err.downcast_ref::<RootMissing>().is_some());
+        assert!(fs::read_to_string(&chronicle).unwrap().contains("a.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_untracked_delete_does_not_fire_triggers() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let repo = temp_dir.path().join("repo");
+        let sage_dir = repo.join(".sage");
+        fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::write(sage_dir.join("valve.yml"), r#"
+personas:
+  Secrets:
+    filters: ["**/*.txt"]
+    triggers: ["secret"]
+    on: [delete]
+  Gone:
+    filters: ["**/*.txt"]
+    on: [delete]
+"#).expect("Failed to write config file");
+        let chronicle = temp_dir.path().join("valve.ndjson");
+        let sinks = Sinks::new(chronicle.clone(), Severity::Info, sink::bus(), HaltStore::at(temp_dir.path().join("halts.json")), RunLog::at(temp_dir.path().join("schedules.json")));
+        let cb = Codebase::new("id".into(), repo.canonicalize().unwrap());
+        let personas = load_personas(&cb, None, &Inputs::default()).await.unwrap();
+        let sinks = sinks.for_codebase(&cb);
+        let mut chron = NdjsonSink::open(&sinks.chronicle, sinks.min_severity).unwrap();
+
+        // never committed, so there is no removed content to judge
+        fs::write(cb.path.join("a.txt"), "secret").unwrap();
+        fs::remove_file(cb.path.join("a.txt")).unwrap();
+        let checkout = Checkout::read(&cb.path).await;
+        evaluate(&cb.path.join("a.txt"), &checkout, Change::Delete, None, &personas, &mut chron, &sinks).await.unwrap();
+        chron.flush().await.unwrap();
+        let raw = fs::read_to_string(&chronicle).unwrap();
+        assert_eq!(raw.lines().count(), 1, "{raw}");
+        assert!(raw.contains("\"persona\":\"Gone\"") && raw.contains("\"reason\":\"delete\""), "{raw}");
+    }
+
+    #[tokio::test]
+    async fn test_stop_drains_pending_paths() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let repo = temp_dir.path().join("repo");
+        let sage_dir = repo.join(".sage");
+        fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::write(sage_dir.join("valve.yml"), r#"
+personas:
+  TestWatcher:
+    filters: ["**/*.txt"]
+"#).expect("Failed to write config file");
+        let chronicle = temp_dir.path().join("valve.ndjson");
+        let sinks = Sinks::new(chronicle.clone(), Severity::Info, sink::bus(), HaltStore::at(temp_dir.path().join("halts.json")), RunLog::at(temp_dir.path().join("schedules.json")));
+        let cb = Codebase::new("id".into(), repo.canonicalize().unwrap());
+
+        let (stop_tx, stop) = tokio::sync::watch::channel(false);
+        // a debounce that would never fire on its own
+        let task = tokio::spawn(async move { watch_codebase(&cb, &sinks, &[], None, &Inputs::default(), Duration::from_secs(60), stop).await });
+        sleep(Duration::from_millis(300)).await;
+        fs::write(repo.join("a.txt"), "x").unwrap();
+        sleep(Duration::from_millis(300)).await;
+        stop_tx.send(true).unwrap();
+        timeout(Duration::from_secs(5), task).await.expect("drains promptly").unwrap().unwrap();
+        assert!(fs::read_to_string(&chronicle).unwrap().contains("a.txt"));
+    }
+
+    #[tokio::test]
+    async fn test_config_edits_reload_in_place_and_packs_go_stale() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let repo = temp_dir.path().join("repo");
+        let sage_dir = repo.join(".sage");
+        fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::create_dir_all(temp_dir.path().join("packs")).expect("Failed to create packs directory");
+        // a pack outside the watched tree
+        fs::write(temp_dir.path().join("packs/docs.yml"), "personas: {}\n").unwrap();
+        fs::write(sage_dir.join("valve.yml"), "include: [../../packs/docs.yml]\npersonas:\n  TestWatcher:\n    filters: [\"**/*.txt\"]\n").unwrap();
+        let chronicle = temp_dir.path().join("valve.ndjson");
+        let sinks = Sinks::new(chronicle.clone(), Severity::Info, sink::bus(), HaltStore::at(temp_dir.path().join("halts.json")), RunLog::at(temp_dir.path().join("schedules.json")));
+        let cb = Codebase::new("id".into(), repo.canonicalize().unwrap());
+        let inputs = Inputs::default();
+
+        let (stop_tx, stop) = tokio::sync::watch::channel(false);
+        let watched = inputs.clone();
+        let task = tokio::spawn(async move { watch_codebase(&cb, &sinks, &[], None, &watched, Duration::from_millis(50), stop).await });
+        sleep(Duration::from_millis(300)).await;
+        assert!(!inputs.stale());
+        fs::write(sage_dir.join("valve.yml"), "include: [../../packs/docs.yml]\npersonas:\n  Docs:\n    filters: [\"**/*.md\"]\n").unwrap();
+        sleep(Duration::from_millis(300)).await;
+        fs::write(repo.join("a.md"), "x").unwrap();
+        sleep(Duration::from_millis(300)).await;
+        assert!(!inputs.stale());
+        fs::write(temp_dir.path().join("packs/docs.yml"), "personas:\n  More: {}\n").unwrap();
+        assert!(inputs.stale());
+        stop_tx.send(true).unwrap();
+        timeout(Duration::from_secs(5), task).await.expect("drains promptly").unwrap().unwrap();
+        assert!(fs::read_to_string(&chronicle).unwrap().contains("a.md"));
+    }
+
+    #[tokio::test]
+    async fn test_missed_schedule_sweeps_once_on_start() {
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let repo = temp_dir.path().join("repo");
+        let sage_dir = repo.join(".sage");
+        fs::create_dir_all(repo.join("cache")).expect("Failed to create cache directory");
+        fs::create_dir_all(&sage_dir).expect("Failed to create .sage directory");
+        fs::write(sage_dir.join("valve.yml"), r#"
+personas:
+  MidnightJanitor:
+    filters: ["cache/**"]
+    schedule: daily
+"#).expect("Failed to write config file");
+        fs::write(repo.join("cache/a.tmp"), "x").unwrap();
+        fs::write(repo.join("cache/b.tmp"), "x").unwrap();
+        let chronicle = temp_dir.path().join("valve.ndjson");
+        let runs = RunLog::at(temp_dir.path().join("schedules.json"));
+        let sinks = Sinks::new(chronicle.clone(), Severity::Info, sink::bus(), HaltStore::at(temp_dir.path().join("halts.json")), runs.clone());
+        let cb = Codebase::new("id".into(), repo.canonicalize().unwrap());
+        // last swept three days ago
+        runs.record(&cb.path, "MidnightJanitor", Local::now() - chrono::Duration::days(3)).unwrap();
+
+        let (stop_tx, stop) = tokio::sync::watch::channel(false);
+        let watched = cb.clone();
+        let task = tokio::spawn(async move { watch_codebase(&watched, &sinks, &[], None, &Inputs::default(), Duration::from_millis(50), stop).await });
+        sleep(Duration::from_millis(300)).await;
+        // scheduled personas ignore changes
+        fs::write(repo.join("cache/c.tmp"), "x").unwrap();
+        sleep(Duration::from_millis(300)).await;
+        stop_tx.send(true).unwrap();
+        timeout(Duration::from_secs(5), task).await.expect("drains promptly").unwrap().unwrap();
+
+        let raw = fs::read_to_string(&chronicle).unwrap();
+        assert_eq!(raw.lines().count(), 1);
+        let ev: serde_json::Value = serde_json::from_str(raw.trim()).unwrap();
+        assert_eq!(ev["file"], ".");
+        assert_eq!(ev["files"], serde_json::json!(["cache/a.tmp", "cache/b.tmp"]));
+        assert!(runs.last(&cb.path, "MidnightJanitor").unwrap().unwrap() > Local::now() - chrono::Duration::minutes(1));
+    }
+
+    #[test]
+    fn test_queue_pairs_renames_and_spots_atomic_saves() {
+        use notify::event::{CreateKind, RemoveKind};
+        let temp_dir = TempDir::new().expect("Failed to create temp directory");
+        let (a, b, tmp, gone) = (temp_dir.path().join("a.rs"), temp_dir.path().join("b.rs"), temp_dir.path().join(".b.rs.swp"), temp_dir.path().join("gone.rs"));
+        let ev = |kind, paths: &[&PathBuf]| paths.iter().fold(notify::Event::new(kind), |e, p| e.add_path(p.to_path_buf()));
+        let name = |mode| EventKind::Modify(ModifyKind::Name(mode));
+        let mut pending = HashMap::new();
+        let mut feed = |e| queue(&mut pending, e, &[], Duration::ZERO);
+
+        // inotify reports a rename as from, to, then both
+        fs::write(&b, "x").unwrap();
+        feed(ev(name(RenameMode::From), &[&a]));
+        feed(ev(name(RenameMode::To), &[&b]));
+        feed(ev(name(RenameMode::Both), &[&a, &b]));
+        // written to a temp file, then moved over the original
+        fs::write(&tmp, "x").unwrap();
+        feed(ev(EventKind::Create(CreateKind::File), &[&tmp]));
+        fs::remove_file(&tmp).unwrap();
+        feed(ev(name(RenameMode::From), &[&tmp]));
+        feed(ev(name(RenameMode::Both), &[&tmp, &a]));
+        // created and removed within the window
+        feed(ev(EventKind::Create(CreateKind::File), &[&gone]));
+        feed(ev(EventKind::Remove(RemoveKind::File), &[&gone]));
+
+        let summary = |p: &Path| pending.get(p).map(|e: &Pending| (e.change, e.from.clone(), e.fresh));
+        assert_eq!(pending.len(), 3);
+        assert_eq!(summary(&b), Some((Change::Rename, Some(a.clone()), false)));
+        assert_eq!(summary(&a), Some((Change::Modify, None, false)));
+        assert_eq!(summary(&gone), Some((Change::Delete, None, true)));
+    }
+}